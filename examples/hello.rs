@@ -9,18 +9,18 @@ struct MyFlag;
 
 fn hello_there(
     commands: &mut Commands,
-    query: Query<(MyComponent,), ()>,
-    flag_query: Query<(MyFlag,), ()>,
+    query: Query<(&MyComponent,), ()>,
+    flag_query: Query<(&MyFlag,), ()>,
 ) {
-    if flag_query.results.is_empty() {
+    if flag_query.is_empty() {
         commands.spawn((MyComponent("Hello".to_owned()),));
         commands.spawn((MyComponent("Not Hello".to_owned()),));
         commands.spawn((MyFlag,));
     }
 
     println!("Printing!");
-    for result in &query.results {
-        dbg!(result);
+    for result in &query {
+        dbg!(&result);
         commands.despawn(result.entity);
     }
 }
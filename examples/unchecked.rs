@@ -8,7 +8,10 @@ fn duplicatecommandssystem(_commands: &mut Commands, _othercommands: &mut Comman
     println!("Two mutable references to commands but running anyway!");
 }
 
-fn mustrestrictsystem(_query: Query<(MyComponent,), ()>, _query2: Query<(MyComponent,), ()>) {
+fn mustrestrictsystem(
+    _query: Query<(&mut MyComponent,), ()>,
+    _query2: Query<(&mut MyComponent,), ()>,
+) {
     println!("Two Repeated Queries but running anyway!")
 }
 
@@ -0,0 +1,91 @@
+use std::ops::{Deref, DerefMut};
+
+/// A point in time of the world, advanced every time a system runs or the entities change
+pub(crate) type Tick = u64;
+
+/// When a component of an entity was added and last changed
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ComponentTicks {
+    pub(crate) added: Tick,
+    pub(crate) changed: Tick,
+}
+
+impl ComponentTicks {
+    pub(crate) fn new(tick: Tick) -> Self {
+        Self {
+            added: tick,
+            changed: tick,
+        }
+    }
+}
+
+/// The ticks of the system currently running. A change is new to the system if it happened
+/// after the system last ran.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemTicks {
+    pub(crate) last_run: Tick,
+    pub(crate) this_run: Tick,
+}
+
+impl SystemTicks {
+    pub(crate) fn is_new(&self, tick: Tick) -> bool {
+        tick > self.last_run
+    }
+}
+
+/// Mutable access to a component that marks it as changed when it is written to
+pub struct Mut<'a, T> {
+    value: &'a mut T,
+    ticks: &'a mut ComponentTicks,
+    system_ticks: SystemTicks,
+}
+
+impl<'a, T> Mut<'a, T> {
+    pub(crate) fn new(
+        value: &'a mut T,
+        ticks: &'a mut ComponentTicks,
+        system_ticks: SystemTicks,
+    ) -> Self {
+        Self {
+            value,
+            ticks,
+            system_ticks,
+        }
+    }
+
+    /// Whether the component was added since the system last ran
+    pub fn is_added(&self) -> bool {
+        self.system_ticks.is_new(self.ticks.added)
+    }
+
+    /// Whether the component was added or changed since the system last ran
+    pub fn is_changed(&self) -> bool {
+        self.system_ticks.is_new(self.ticks.changed)
+    }
+
+    /// Gives mutable access to the component without marking it as changed
+    pub fn bypass_change_detection(&mut self) -> &mut T {
+        self.value
+    }
+}
+
+impl<T> Deref for Mut<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.value
+    }
+}
+
+impl<T> DerefMut for Mut<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.ticks.changed = self.system_ticks.this_run;
+        self.value
+    }
+}
+
+impl<T: std::fmt::Debug> std::fmt::Debug for Mut<'_, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.value.fmt(f)
+    }
+}
@@ -0,0 +1,143 @@
+use std::{fmt, marker::PhantomData};
+
+use crate::{World, component::ComponentBundle, entity::EntityId};
+
+/// A mutation of the world queued with `Commands::add`, applied with full access to the world
+/// once the commands are applied. Closures taking the world and returning either nothing or a
+/// `Result` are commands too.
+pub trait Command: Send + 'static {
+    /// An error is handled according to the world's `CommandErrorPolicy`
+    fn apply(self, world: &mut World) -> Result<(), CommandError>;
+}
+
+impl<F, Out> Command for F
+where
+    F: FnOnce(&mut World) -> Out + Send + 'static,
+    Out: IntoCommandResult,
+{
+    fn apply(self, world: &mut World) -> Result<(), CommandError> {
+        self(world).into_command_result()
+    }
+}
+
+/// What a closure queued as a command can return
+pub trait IntoCommandResult {
+    fn into_command_result(self) -> Result<(), CommandError>;
+}
+
+impl IntoCommandResult for () {
+    fn into_command_result(self) -> Result<(), CommandError> {
+        Ok(())
+    }
+}
+
+impl IntoCommandResult for Result<(), CommandError> {
+    fn into_command_result(self) -> Result<(), CommandError> {
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandError {
+    /// The entity the command was queued for does not exist anymore, or never did
+    EntityNotFound(EntityId),
+    /// Making `child` a child of `parent` would make it its own ancestor
+    HierarchyCycle { parent: EntityId, child: EntityId },
+    /// An error returned by a custom command
+    Custom(String),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::EntityNotFound(entity) => write!(
+                f,
+                "The command was queued for entity {entity}, which does not exist"
+            ),
+            Self::HierarchyCycle { parent, child } => write!(
+                f,
+                "Entity {child} cannot become a child of entity {parent}, since it would be its own ancestor"
+            ),
+            Self::Custom(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+/// What the world does when a command fails
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CommandErrorPolicy {
+    #[default]
+    Panic,
+    /// Logs the error with `log::error!`
+    Log,
+    Ignore,
+    /// Keeps the error until it is taken with `World::take_command_errors`
+    Collect,
+}
+
+/// Fails with `CommandError::EntityNotFound` if the entity does not exist
+fn ensure_exists(world: &World, entity: EntityId) -> Result<(), CommandError> {
+    if world.contains(entity) {
+        Ok(())
+    } else {
+        Err(CommandError::EntityNotFound(entity))
+    }
+}
+
+/// Spawns an entity with an id reserved by `Commands::spawn`
+pub(crate) struct Spawn<B> {
+    pub(crate) entity: EntityId,
+    pub(crate) components: B,
+}
+
+impl<B: ComponentBundle> Command for Spawn<B> {
+    fn apply(self, world: &mut World) -> Result<(), CommandError> {
+        world.spawn_reserved(self.entity, self.components);
+        Ok(())
+    }
+}
+
+pub(crate) struct Despawn {
+    pub(crate) entity: EntityId,
+    /// Whether a missing entity is an error rather than expected
+    pub(crate) strict: bool,
+}
+
+impl Command for Despawn {
+    fn apply(self, world: &mut World) -> Result<(), CommandError> {
+        if world.contains(self.entity) {
+            world.despawn(&self.entity);
+        } else if self.strict {
+            return Err(CommandError::EntityNotFound(self.entity));
+        }
+        Ok(())
+    }
+}
+
+pub(crate) struct Insert<B> {
+    pub(crate) entity: EntityId,
+    pub(crate) components: B,
+}
+
+impl<B: ComponentBundle> Command for Insert<B> {
+    fn apply(self, world: &mut World) -> Result<(), CommandError> {
+        ensure_exists(world, self.entity)?;
+        world.insert(self.entity, self.components);
+        Ok(())
+    }
+}
+
+pub(crate) struct Remove<B> {
+    pub(crate) entity: EntityId,
+    pub(crate) _marker: PhantomData<fn() -> B>,
+}
+
+impl<B: ComponentBundle> Command for Remove<B> {
+    fn apply(self, world: &mut World) -> Result<(), CommandError> {
+        ensure_exists(world, self.entity)?;
+        world.remove::<B>(self.entity);
+        Ok(())
+    }
+}
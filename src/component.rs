@@ -5,15 +5,57 @@ use std::{
 
 use any_vec::{AnyVec, any_value::AnyValueWrapper};
 
-use crate::{EntityId, EntityManager, entity::EntityBitmask};
+use crate::{
+    World,
+    entity::{ComponentInserter, EntityBitmask, EntityId},
+};
+
+pub trait Component: Send + Sync + 'static {
+    /// Registers the hooks of the component. Called once, when the component is registered.
+    fn register_hooks(_hooks: &mut ComponentHooks) {}
+}
+
+/// Runs with access to the world whenever a component is added to or removed from an entity
+pub type ComponentHook = fn(&mut World, EntityId);
+
+/// The hooks of a component type
+#[derive(Default, Clone, Copy)]
+pub struct ComponentHooks {
+    pub(crate) on_add: Option<ComponentHook>,
+    pub(crate) on_insert: Option<ComponentHook>,
+    pub(crate) on_remove: Option<ComponentHook>,
+}
 
-pub trait Component: 'static {}
+impl ComponentHooks {
+    /// Runs when the component is added to an entity that did not have it
+    pub fn on_add(&mut self, hook: ComponentHook) -> &mut Self {
+        self.on_add = Some(hook);
+        self
+    }
+
+    /// Runs whenever the component is inserted, including when it replaces an existing value
+    pub fn on_insert(&mut self, hook: ComponentHook) -> &mut Self {
+        self.on_insert = Some(hook);
+        self
+    }
+
+    /// Runs before the component is removed from an entity or the entity is despawned, so the
+    /// component can still be read
+    pub fn on_remove(&mut self, hook: ComponentHook) -> &mut Self {
+        self.on_remove = Some(hook);
+        self
+    }
+}
 
 pub(crate) type ComponentId = usize;
 #[derive(Default)]
 pub struct ComponentManager {
     components: HashMap<TypeId, ComponentId>,
     last_used_id: ComponentId,
+    /// Indexed by component id
+    hooks: Vec<ComponentHooks>,
+    /// The components that may have hooks, so the ones without any can be skipped
+    hooked: EntityBitmask,
 }
 
 impl ComponentManager {
@@ -31,11 +73,32 @@ impl ComponentManager {
         let id = self.get_new_id();
         let result = self.components.insert(TypeId::of::<C>(), id);
         debug_assert!(result.is_none());
+        let mut hooks = ComponentHooks::default();
+        C::register_hooks(&mut hooks);
+        if hooks.on_add.is_some() || hooks.on_insert.is_some() || hooks.on_remove.is_some() {
+            self.hooked.insert(id);
+        }
+        self.hooks.push(hooks);
         id
     }
 
+    pub(crate) fn hooks(&self, component: ComponentId) -> ComponentHooks {
+        self.hooks[component]
+    }
+
+    /// The hooks are about to be set, so the component is assumed to have some from now on
+    pub(crate) fn hooks_mut(&mut self, component: ComponentId) -> &mut ComponentHooks {
+        self.hooked.insert(component);
+        &mut self.hooks[component]
+    }
+
+    /// Whether any of the components may have a hook
+    pub(crate) fn has_hooks(&self, components: &EntityBitmask) -> bool {
+        !self.hooked.is_disjoint(components)
+    }
+
     pub(crate) fn get_component_id<C: Component>(&self) -> Option<ComponentId> {
-        self.components.get(&TypeId::of::<C>()).map(|&id| id)
+        self.components.get(&TypeId::of::<C>()).copied()
     }
 
     pub(crate) fn register_component_if_not_exists<C: Component>(&mut self) -> ComponentId {
@@ -49,13 +112,35 @@ impl ComponentManager {
     }
 }
 
-pub trait ComponentBundle {
-    fn spawn(
-        self,
-        id: EntityId,
-        entity_manager: &mut EntityManager,
+/// Creates the empty column of a component
+pub(crate) type NewColumn = fn() -> AnyVec;
+
+/// The type-erased components of a bundle, ordered by component id like the columns of an
+/// archetype
+pub struct BundleComponents {
+    pub(crate) bitmask: EntityBitmask,
+    pub(crate) default_columns: Box<[fn() -> AnyVec]>,
+    pub(crate) inserters: Box<[ComponentInserter]>,
+}
+
+pub trait ComponentBundle: Send + 'static {
+    /// Registers the components of the bundle if needed and returns their bitmask
+    fn bitmask(component_manager: &mut ComponentManager) -> EntityBitmask
+    where
+        Self: Sized;
+
+    fn into_components(self, component_manager: &mut ComponentManager) -> BundleComponents;
+
+    /// Registers the components of the bundle if needed and returns their ids along with the
+    /// constructors of their columns, in the order of the bundle
+    fn component_columns(
         component_manager: &mut ComponentManager,
-    );
+    ) -> Box<[(ComponentId, NewColumn)]>
+    where
+        Self: Sized;
+
+    /// The inserters of the components, in the order of the bundle
+    fn into_inserters(self) -> Box<[ComponentInserter]>;
 }
 
 macro_rules! replace_expr {
@@ -67,19 +152,25 @@ macro_rules! impl_component_bundle {
     ($(($n:tt, $B:ident)),*) => {
         impl<$($B: Component),*> ComponentBundle for ($($B,)*) {
             #[allow(unused_variables, unused_mut)]
-            fn spawn(
-                self,
-                id: EntityId,
-                entity_manager: &mut EntityManager,
-                component_manager: &mut ComponentManager,
-            ) {
+            fn bitmask(component_manager: &mut ComponentManager) -> EntityBitmask {
+                let mut bitmask = EntityBitmask::default();
+                $({
+                    let id = component_manager.register_component_if_not_exists::<$B>();
+                    let had_inserted = bitmask.insert(id);
+                    assert!(had_inserted, "duplicate component type in entity");
+                })*
+                bitmask
+            }
+
+            #[allow(unused_variables, unused_mut)]
+            fn into_components(self, component_manager: &mut ComponentManager) -> BundleComponents {
                 let len = <[()]>::len(&[$(replace_expr!($n ())),*]);
                 let mut bitmask = EntityBitmask::default();
                 let mut components_btree = BTreeMap::<usize, usize>::new();
                 $({
                     let id = component_manager.register_component_if_not_exists::<$B>();
                     let previous = components_btree.insert(id, $n);
-                    debug_assert!(previous.is_none(), "duplicate component type in entity");
+                    assert!(previous.is_none(), "duplicate component type in entity");
 
                     bitmask.insert(id);
                 })*
@@ -87,14 +178,36 @@ macro_rules! impl_component_bundle {
                 let components_order: HashMap<usize, usize> = components_btree.into_iter().enumerate().map(|(i, (_, v))| (v, i)).collect();
 
                 let mut default_columns = Box::<[fn() -> AnyVec]>::new_uninit_slice(len);
-                let mut inserters = Box::<[Box<dyn FnOnce(&mut AnyVec)>]>::new_uninit_slice(len);
+                let mut inserters = Box::<[ComponentInserter]>::new_uninit_slice(len);
                 $({
                     let current_index = components_order[&$n];
                     default_columns[current_index].write(|| AnyVec::new::<$B>());
                     inserters[current_index].write(Box::new(|v: &mut AnyVec| v.push(AnyValueWrapper::new(self.$n))));
                 })*
 
-                entity_manager.add_entity(id, bitmask, unsafe {default_columns.assume_init()}, unsafe {inserters.assume_init()});
+                BundleComponents {
+                    bitmask,
+                    default_columns: unsafe { default_columns.assume_init() },
+                    inserters: unsafe { inserters.assume_init() },
+                }
+            }
+
+            #[allow(unused_variables)]
+            fn component_columns(
+                component_manager: &mut ComponentManager,
+            ) -> Box<[(ComponentId, NewColumn)]> {
+                Box::new([$((
+                    component_manager.register_component_if_not_exists::<$B>(),
+                    (|| AnyVec::new::<$B>()) as NewColumn,
+                ),)*])
+            }
+
+            #[allow(unused_variables)]
+            fn into_inserters(self) -> Box<[ComponentInserter]> {
+                Box::new([$(
+                    Box::new(move |v: &mut AnyVec| v.push(AnyValueWrapper::new(self.$n)))
+                        as ComponentInserter,
+                )*])
             }
         }
     };
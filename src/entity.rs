@@ -1,20 +1,86 @@
+use std::any::TypeId;
+use std::cell::UnsafeCell;
 use std::collections::HashMap;
+use std::fmt;
 use std::ops::Deref;
 use std::ops::DerefMut;
+use std::sync::atomic::{self, AtomicU64};
+use std::sync::{Arc, Mutex};
 
 use any_vec::AnyVec;
 use bit_set::BitSet;
 
 use crate::Component;
 use crate::ComponentBundle;
-use crate::component;
+use crate::change_detection::{ComponentTicks, Tick};
+use crate::component::{self, BundleComponents, ComponentId};
+use crate::event::Events;
+use crate::removal_detection::RemovedEntity;
 
+/// A handle to an entity. The index of a despawned entity is reused by later spawns, but with a
+/// bumped generation, so an old handle never refers to the new entity.
 #[derive(Hash, Default, Debug, PartialEq, Eq, Clone, Copy)]
-pub struct EntityId(usize);
+pub struct EntityId {
+    index: usize,
+    generation: u32,
+}
 
 impl EntityId {
-    pub(crate) fn new(id: usize) -> Self {
-        Self(id)
+    pub(crate) fn new(index: usize, generation: u32) -> Self {
+        Self { index, generation }
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+}
+
+impl fmt::Display for EntityId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}v{}", self.index, self.generation)
+    }
+}
+
+#[derive(Default, Debug)]
+struct EntityAllocatorState {
+    /// The current generation of every index ever handed out
+    generations: Vec<u32>,
+    /// Indexes whose entity has been despawned and that can be handed out again
+    free_indexes: Vec<usize>,
+}
+
+/// Hands out entity ids, reusing the indexes of despawned entities.
+/// This is a shared handle: `Commands` reserves ids while the `EntityManager` frees them.
+#[derive(Default, Debug, Clone)]
+pub(crate) struct EntityAllocator(Arc<Mutex<EntityAllocatorState>>);
+
+impl EntityAllocator {
+    pub(crate) fn alloc(&self) -> EntityId {
+        let mut state = self.0.lock().unwrap();
+        match state.free_indexes.pop() {
+            Some(index) => EntityId::new(index, state.generations[index]),
+            None => {
+                state.generations.push(0);
+                EntityId::new(state.generations.len() - 1, 0)
+            }
+        }
+    }
+
+    /// Returns the index of `id` to the free list. Returns false if `id` was already stale.
+    pub(crate) fn free(&self, id: EntityId) -> bool {
+        let mut state = self.0.lock().unwrap();
+        match state.generations.get_mut(id.index) {
+            Some(generation) if *generation == id.generation => {
+                *generation = generation.wrapping_add(1);
+                state.free_indexes.push(id.index);
+                true
+            }
+            _ => false,
+        }
     }
 }
 
@@ -37,6 +103,12 @@ impl EntityBitmask {
     }
 }
 
+impl FromIterator<usize> for EntityBitmask {
+    fn from_iter<T: IntoIterator<Item = usize>>(iter: T) -> Self {
+        Self::new(iter.into_iter().collect())
+    }
+}
+
 impl From<BitSet> for EntityBitmask {
     fn from(value: BitSet) -> Self {
         Self::new(value)
@@ -51,22 +123,89 @@ impl std::ops::Deref for EntityBitmask {
     }
 }
 
+/// The ticks of a single value. Queries running on several threads write them through a shared
+/// reference to the columns, each thread to its own rows.
+#[derive(Debug)]
+pub(crate) struct TicksCell(UnsafeCell<ComponentTicks>);
+
+// SAFETY: Ticks are only written by the one query granted mutable access to their column, and
+// every row by a single thread
+unsafe impl Sync for TicksCell {}
+
+impl TicksCell {
+    fn new(tick: Tick) -> Self {
+        Self(UnsafeCell::new(ComponentTicks::new(tick)))
+    }
+}
+
+/// The component values of an archetype, one column per component, with the ticks of every
+/// value stored alongside
 #[derive(Debug)]
-pub struct ComponentColumns(Box<[AnyVec]>);
+pub struct ComponentColumns {
+    values: Box<[AnyVec]>,
+    ticks: Box<[Vec<TicksCell>]>,
+}
 
 impl ComponentColumns {
-    fn new(columns: Box<[AnyVec]>) -> Self {
-        Self(columns)
+    fn new(values: Box<[AnyVec]>) -> Self {
+        Self {
+            ticks: values.iter().map(|_| Vec::new()).collect(),
+            values,
+        }
     }
 
-    pub(crate) fn get_mut_from_column<C: Component>(
+    pub(crate) fn get_from_column<C: Component>(&self, column: usize, index: usize) -> Option<&C> {
+        self[column]
+            .get(index)
+            .and_then(|val| val.downcast_ref::<C>())
+    }
+
+    /// Returns the component along with its ticks, for change detection
+    pub(crate) fn get_mut_with_ticks<C: Component>(
         &mut self,
         column: usize,
         index: usize,
-    ) -> Option<&mut C> {
-        self[column]
+    ) -> Option<(&mut C, &mut ComponentTicks)> {
+        let value = self.values[column]
             .get_mut(index)
-            .and_then(|mut val| val.downcast_mut::<C>())
+            .and_then(|mut val| val.downcast_mut::<C>())?;
+        Some((value, self.ticks[column][index].0.get_mut()))
+    }
+
+    /// A pointer to the value in the row of the column, derived from the column's buffer rather
+    /// than from a mutable reference to the columns, so several threads can write to different
+    /// rows of the same archetype
+    /// SAFETY: `C` must be the component of the column, and the row must exist
+    pub(crate) unsafe fn value_ptr<C: Component>(&self, column: usize, row: usize) -> *mut C {
+        debug_assert!(row < self.values[column].len());
+        let values = self.values[column].downcast_ref::<C>().unwrap();
+        // The pointer is the one `AnyVec` keeps to its heap buffer, so it may be written through
+        unsafe { values.as_ptr().cast_mut().add(row) }
+    }
+
+    /// A pointer to the ticks of the value in the row of the column, see `value_ptr`
+    pub(crate) fn ticks_ptr(&self, column: usize, row: usize) -> *mut ComponentTicks {
+        self.ticks[column][row].0.get()
+    }
+
+    /// Moves the values and ticks of the row into the columns of `target` given by
+    /// `target_columns`, dropping the ones without a target column
+    fn move_row(&mut self, row: usize, target: &mut Self, target_columns: &[Option<usize>]) {
+        for (column, target_column) in target_columns.iter().enumerate() {
+            let value = self.values[column].swap_remove(row);
+            let ticks = self.ticks[column].swap_remove(row);
+            if let Some(target_column) = *target_column {
+                target.values[target_column].push(value);
+                target.ticks[target_column].push(ticks);
+            }
+        }
+    }
+
+    fn remove_row(&mut self, row: usize) {
+        for (values, ticks) in self.values.iter_mut().zip(self.ticks.iter_mut()) {
+            values.swap_remove(row);
+            ticks.swap_remove(row);
+        }
     }
 }
 
@@ -74,111 +213,466 @@ impl Deref for ComponentColumns {
     type Target = Box<[AnyVec]>;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.values
     }
 }
 
-impl DerefMut for ComponentColumns {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+/// A cached structural change from one archetype to another
+#[derive(Debug)]
+pub(crate) struct ArchetypeEdge {
+    pub(crate) target: ArchetypeId,
+    /// For every column of the source archetype, the column of the target archetype its
+    /// values move to, or `None` if they are dropped
+    pub(crate) source_columns: Box<[Option<usize>]>,
+    /// For every component of the inserted bundle, in the order of the bundle, its column in
+    /// the target
+    pub(crate) bundle_columns: Box<[usize]>,
+    /// The components of the inserted bundle
+    pub(crate) inserted: EntityBitmask,
+    /// The inserted components the entities of the source archetype did not have yet
+    pub(crate) added: EntityBitmask,
+}
+
+impl ArchetypeEdge {
+    fn new(
+        source_bitmask: &EntityBitmask,
+        bundle: &[ComponentId],
+        target: ArchetypeId,
+        target_bitmask: &EntityBitmask,
+    ) -> Self {
+        let inserted: EntityBitmask = bundle.iter().copied().collect();
+        Self {
+            target,
+            source_columns: source_bitmask
+                .iter()
+                .map(|component| column_of(target_bitmask, component))
+                .collect(),
+            bundle_columns: bundle
+                .iter()
+                .map(|&component| column_of(target_bitmask, component).unwrap())
+                .collect(),
+            added: inserted.difference(source_bitmask).collect(),
+            inserted,
+        }
     }
 }
 
+/// Edges are keyed by the `TypeId` of the inserted or removed bundle, so single components get
+/// one edge each
+#[derive(Debug, Default)]
+pub(crate) struct ArchetypeEdges {
+    pub(crate) insert: HashMap<TypeId, Arc<ArchetypeEdge>>,
+    pub(crate) remove: HashMap<TypeId, Arc<ArchetypeEdge>>,
+}
+
 #[derive(Debug)]
 pub(crate) struct Archetype {
+    pub(crate) bitmask: EntityBitmask,
     pub(crate) entities: Vec<EntityId>,
     pub(crate) component_columns: ComponentColumns,
+    pub(crate) edges: ArchetypeEdges,
 }
 
 impl Archetype {
-    pub(crate) fn new(component_columns: Box<[AnyVec]>) -> Self {
+    pub(crate) fn new(bitmask: EntityBitmask, component_columns: Box<[AnyVec]>) -> Self {
         Self {
+            bitmask,
             entities: Vec::default(),
             component_columns: ComponentColumns::new(component_columns),
+            edges: ArchetypeEdges::default(),
         }
     }
 }
 
+pub(crate) type ArchetypeId = usize;
+
+/// Returns the index of the column storing `component` in an archetype with the given bitmask
+pub(crate) fn column_of(
+    bitmask: &EntityBitmask,
+    component: component::ComponentId,
+) -> Option<usize> {
+    bitmask
+        .contains(component)
+        .then(|| bitmask.iter().take_while(|&id| id < component).count())
+}
+
+/// Where the components of an entity are stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct EntityLocation {
+    pub(crate) archetype: ArchetypeId,
+    pub(crate) row: usize,
+}
+
+pub(crate) type ComponentInserter = Box<dyn FnOnce(&mut AnyVec)>;
+
 #[derive(Default, Debug)]
 pub struct EntityManager {
-    pub(crate) archetypes: HashMap<EntityBitmask, Archetype>,
+    pub(crate) archetypes: Vec<Archetype>,
+    archetype_ids: HashMap<EntityBitmask, ArchetypeId>,
+    /// Indexed by `EntityId::index`
+    locations: Vec<Option<EntityLocation>>,
+    allocator: EntityAllocator,
+    /// Atomic since systems running in parallel advance it
+    change_tick: AtomicU64,
+    /// Indexed by component id, the entities that lost the component during the last two
+    /// updates, or `None` if nothing reads them
+    removed: Vec<Option<Events<RemovedEntity>>>,
 }
 
 impl EntityManager {
+    /// Advances the world's change tick, returning the new one
+    pub(crate) fn increment_change_tick(&self) -> Tick {
+        self.change_tick.fetch_add(1, atomic::Ordering::Relaxed) + 1
+    }
+
+    pub(crate) fn removed(
+        &self,
+        component: component::ComponentId,
+    ) -> Option<&Events<RemovedEntity>> {
+        self.removed.get(component)?.as_ref()
+    }
+
+    /// Starts recording the entities that lose the component, which is only done for the
+    /// components a `RemovedComponents` reads so the removals are not kept for nothing
+    pub(crate) fn track_removed(&mut self, component: component::ComponentId) {
+        if self.removed.len() <= component {
+            self.removed.resize_with(component + 1, || None);
+        }
+        self.removed[component].get_or_insert_with(Default::default);
+    }
+
+    fn record_removed(&mut self, component: component::ComponentId, entity_id: EntityId) {
+        if let Some(Some(removed)) = self.removed.get_mut(component) {
+            removed.send(RemovedEntity(entity_id));
+        }
+    }
+
+    /// Drops the removals recorded two updates ago
+    pub(crate) fn update_removed(&mut self) {
+        for removed in self.removed.iter_mut().flatten() {
+            removed.update();
+        }
+    }
+
+    pub(crate) fn allocator(&self) -> EntityAllocator {
+        self.allocator.clone()
+    }
+
     pub(crate) fn spawn(
         &mut self,
         new_entity_id: EntityId,
         components: impl ComponentBundle,
         components_manager: &mut component::ComponentManager,
     ) {
-        components.spawn(new_entity_id, self, components_manager);
+        let components = components.into_components(components_manager);
+        self.add_entity(new_entity_id, components);
     }
 
-    pub(crate) fn add_entity(
-        &mut self,
-        id: EntityId,
-        bitmask: EntityBitmask,
-        default_columns: Box<[fn() -> AnyVec]>,
-        inserters: Box<[Box<dyn FnOnce(&mut AnyVec)>]>,
-    ) {
-        let archetype = self
-            .archetypes
-            .entry(bitmask)
-            .or_insert_with(|| Archetype::new(default_columns.iter().map(|f| f()).collect()));
+    pub(crate) fn add_entity(&mut self, id: EntityId, components: BundleComponents) {
+        let tick = self.increment_change_tick();
+        let BundleComponents {
+            bitmask,
+            default_columns,
+            inserters,
+        } = components;
+        let archetype_id = self.archetype_id_or_insert_with(bitmask, |_| {
+            default_columns.iter().map(|f| f()).collect()
+        });
+        let archetype = &mut self.archetypes[archetype_id];
 
+        let row = archetype.entities.len();
         archetype.entities.push(id);
-
-        for (column, inserter) in archetype
-            .component_columns
+        let columns = &mut archetype.component_columns;
+        for ((values, ticks), inserter) in columns
+            .values
             .iter_mut()
-            .zip(inserters.into_iter())
+            .zip(columns.ticks.iter_mut())
+            .zip(inserters)
         {
-            inserter(column);
+            inserter(values);
+            ticks.push(TicksCell::new(tick));
         }
+
+        self.set_location(
+            &id,
+            Some(EntityLocation {
+                archetype: archetype_id,
+                row,
+            }),
+        );
     }
 
-    // TODO: Change this to something more sane
-    #[cfg(test)]
-    pub(crate) fn entity_exists(&self, entity_id: &EntityId) -> bool {
-        self.archetypes
-            .values()
-            .any(|a| a.entities.iter().any(|e| e == entity_id))
+    /// Adds the components to an existing entity, moving it to another archetype if needed.
+    /// Components the entity already has are overwritten. Returns the edge the entity took,
+    /// which tells which components were added.
+    pub(crate) fn insert<B: ComponentBundle>(
+        &mut self,
+        entity_id: &EntityId,
+        components: B,
+        components_manager: &mut component::ComponentManager,
+    ) -> Arc<ArchetypeEdge> {
+        let mut location = self
+            .location(entity_id)
+            .expect("Attempted to insert components into non-existent entity!");
+        let edge = self.insert_edge::<B>(location.archetype, components_manager);
+
+        if edge.target != location.archetype {
+            location = self.move_entity(entity_id, location, &edge);
+        }
+
+        let tick = self.increment_change_tick();
+        let columns = &mut self.archetypes[location.archetype].component_columns;
+        for (&column, inserter) in edge.bundle_columns.iter().zip(components.into_inserters()) {
+            let values = &mut columns.values[column];
+            let ticks = &mut columns.ticks[column];
+            inserter(values);
+            // The entity already had this component, so the new value replaces the old one
+            if values.len() > location.row + 1 {
+                values.swap_remove(location.row);
+                ticks[location.row].0.get_mut().changed = tick;
+            } else {
+                ticks.push(TicksCell::new(tick));
+            }
+        }
+        edge
     }
 
-    // TODO: Change this to something more sane
-    pub(crate) fn despawn(&mut self, entity_id: &EntityId) {
-        let (archetype, entity_index) = self
-            .archetypes
-            .values_mut()
-            .find_map(|archetype| {
-                archetype
-                    .entities
-                    .iter()
-                    .position(|current_entity| current_entity == entity_id)
-                    .map(|entity_index| (archetype, entity_index))
-            })
-            .expect("Attempted to despawn non-existent entity!");
+    /// Removes the components of the bundle the entity has, moving it to another archetype.
+    /// Components the entity does not have are ignored.
+    pub(crate) fn remove<B: ComponentBundle>(
+        &mut self,
+        entity_id: &EntityId,
+        components_manager: &mut component::ComponentManager,
+    ) {
+        let location = self
+            .location(entity_id)
+            .expect("Attempted to remove components from non-existent entity!");
+        let edge = self.remove_edge::<B>(location.archetype, components_manager);
+
+        if edge.target != location.archetype {
+            let source_bitmask = self.archetypes[location.archetype].bitmask.clone();
+            for (component, target_column) in source_bitmask.iter().zip(&edge.source_columns) {
+                if target_column.is_none() {
+                    self.record_removed(component, *entity_id);
+                }
+            }
+            self.move_entity(entity_id, location, &edge);
+        }
+    }
+
+    /// Returns the cached edge for inserting `B` into the archetype, creating it (and the target
+    /// archetype) on first use
+    fn insert_edge<B: ComponentBundle>(
+        &mut self,
+        source: ArchetypeId,
+        components_manager: &mut component::ComponentManager,
+    ) -> Arc<ArchetypeEdge> {
+        if let Some(edge) = self.archetypes[source].edges.insert.get(&TypeId::of::<B>()) {
+            return edge.clone();
+        }
 
-        archetype.entities.swap_remove(entity_index);
-        for component_list in archetype.component_columns.iter_mut() {
-            component_list.swap_remove(entity_index);
+        let columns = B::component_columns(components_manager);
+        let bundle: Box<[ComponentId]> = columns.iter().map(|&(component, _)| component).collect();
+        let bitmask: EntityBitmask = bundle.iter().copied().collect();
+        assert_eq!(
+            bitmask.len(),
+            bundle.len(),
+            "duplicate component type in entity"
+        );
+        let source_bitmask = &self.archetypes[source].bitmask;
+        let target_bitmask: EntityBitmask = source_bitmask.union(&bitmask).collect();
+        let target = self.archetype_id_or_insert_with(target_bitmask, |manager| {
+            let source = &manager.archetypes[source];
+            source
+                .bitmask
+                .union(&bitmask)
+                .map(|component| match column_of(&source.bitmask, component) {
+                    Some(column) => source.component_columns[column].clone_empty(),
+                    None => {
+                        let (_, new_column) =
+                            columns.iter().find(|(id, _)| *id == component).unwrap();
+                        new_column()
+                    }
+                })
+                .collect()
+        });
+
+        let edge = Arc::new(ArchetypeEdge::new(
+            &self.archetypes[source].bitmask,
+            &bundle,
+            target,
+            &self.archetypes[target].bitmask,
+        ));
+        self.archetypes[source]
+            .edges
+            .insert
+            .insert(TypeId::of::<B>(), edge.clone());
+        edge
+    }
+
+    /// Returns the cached edge for removing `B` from the archetype, creating it (and the target
+    /// archetype) on first use
+    fn remove_edge<B: ComponentBundle>(
+        &mut self,
+        source: ArchetypeId,
+        components_manager: &mut component::ComponentManager,
+    ) -> Arc<ArchetypeEdge> {
+        if let Some(edge) = self.archetypes[source].edges.remove.get(&TypeId::of::<B>()) {
+            return edge.clone();
         }
+
+        let bitmask = B::bitmask(components_manager);
+        let target_bitmask: EntityBitmask = self.archetypes[source]
+            .bitmask
+            .difference(&bitmask)
+            .collect();
+        let target = self.archetype_id_or_insert_with(target_bitmask, |manager| {
+            let source = &manager.archetypes[source];
+            source
+                .bitmask
+                .iter()
+                .zip(source.component_columns.iter())
+                .filter(|(component, _)| !bitmask.contains(*component))
+                .map(|(_, column)| column.clone_empty())
+                .collect()
+        });
+
+        let edge = Arc::new(ArchetypeEdge::new(
+            &self.archetypes[source].bitmask,
+            &[],
+            target,
+            &self.archetypes[target].bitmask,
+        ));
+        self.archetypes[source]
+            .edges
+            .remove
+            .insert(TypeId::of::<B>(), edge.clone());
+        edge
     }
 
-    pub(crate) fn query(
+    fn archetype_id_or_insert_with(
         &mut self,
-        query_bitmask: &EntityBitmask,
-        restrictions_bitmask: &EntityBitmask,
-    ) -> Box<[(&EntityBitmask, &mut Archetype)]> {
+        bitmask: EntityBitmask,
+        columns: impl FnOnce(&Self) -> Box<[AnyVec]>,
+    ) -> ArchetypeId {
+        if let Some(&archetype_id) = self.archetype_ids.get(&bitmask) {
+            return archetype_id;
+        }
+        let archetype_id = self.archetypes.len();
+        let columns = columns(self);
         self.archetypes
-            .iter_mut()
-            .filter_map(|(bitmask, archetype)| {
-                if bitmask.matches_query(query_bitmask, restrictions_bitmask) {
-                    Some((bitmask, archetype))
-                } else {
-                    None
-                }
+            .push(Archetype::new(bitmask.clone(), columns));
+        self.archetype_ids.insert(bitmask, archetype_id);
+        archetype_id
+    }
+
+    /// Moves the entity's components into the target archetype of the edge. Components the
+    /// target archetype does not store are dropped.
+    fn move_entity(
+        &mut self,
+        entity_id: &EntityId,
+        location: EntityLocation,
+        edge: &ArchetypeEdge,
+    ) -> EntityLocation {
+        let [source, target] = self
+            .archetypes
+            .get_disjoint_mut([location.archetype, edge.target])
+            .unwrap();
+
+        source.component_columns.move_row(
+            location.row,
+            &mut target.component_columns,
+            &edge.source_columns,
+        );
+        source.entities.swap_remove(location.row);
+        let moved = source.entities.get(location.row).copied();
+
+        let new_location = EntityLocation {
+            archetype: edge.target,
+            row: target.entities.len(),
+        };
+        target.entities.push(*entity_id);
+
+        if let Some(moved) = moved {
+            self.set_location(&moved, Some(location));
+        }
+        self.set_location(entity_id, Some(new_location));
+        new_location
+    }
+
+    fn set_location(&mut self, entity_id: &EntityId, location: Option<EntityLocation>) {
+        if self.locations.len() <= entity_id.index {
+            self.locations.resize(entity_id.index + 1, None);
+        }
+        self.locations[entity_id.index] = location;
+    }
+
+    /// Returns `None` if the entity was despawned, never spawned, or if `entity_id` is stale
+    pub(crate) fn location(&self, entity_id: &EntityId) -> Option<EntityLocation> {
+        self.locations
+            .get(entity_id.index)
+            .copied()
+            .flatten()
+            .filter(|location| {
+                self.archetypes[location.archetype].entities[location.row] == *entity_id
             })
-            .collect()
+    }
+
+    /// The components of the entity, or `None` if it does not exist
+    pub(crate) fn bitmask(&self, entity_id: &EntityId) -> Option<&EntityBitmask> {
+        self.location(entity_id)
+            .map(|location| &self.archetypes[location.archetype].bitmask)
+    }
+
+    pub(crate) fn get<C: Component>(
+        &self,
+        entity_id: &EntityId,
+        component: component::ComponentId,
+    ) -> Option<&C> {
+        let location = self.location(entity_id)?;
+        let archetype = &self.archetypes[location.archetype];
+        let column = column_of(&archetype.bitmask, component)?;
+        archetype
+            .component_columns
+            .get_from_column(column, location.row)
+    }
+
+    /// Returns the component of the entity along with its ticks, for change detection
+    pub(crate) fn get_mut<C: Component>(
+        &mut self,
+        entity_id: &EntityId,
+        component: component::ComponentId,
+    ) -> Option<(&mut C, &mut ComponentTicks)> {
+        let location = self.location(entity_id)?;
+        let archetype = &mut self.archetypes[location.archetype];
+        let column = column_of(&archetype.bitmask, component)?;
+        archetype
+            .component_columns
+            .get_mut_with_ticks(column, location.row)
+    }
+
+    pub(crate) fn contains(&self, entity_id: &EntityId) -> bool {
+        self.location(entity_id).is_some()
+    }
+
+    pub(crate) fn despawn(&mut self, entity_id: &EntityId) {
+        let location = self
+            .location(entity_id)
+            .expect("Attempted to despawn non-existent entity!");
+        let bitmask = self.archetypes[location.archetype].bitmask.clone();
+        for component in bitmask.iter() {
+            self.record_removed(component, *entity_id);
+        }
+        let archetype = &mut self.archetypes[location.archetype];
+
+        archetype.entities.swap_remove(location.row);
+        archetype.component_columns.remove_row(location.row);
+
+        // The last entity of the archetype took the despawned entity's row
+        if let Some(&moved) = archetype.entities.get(location.row) {
+            self.set_location(&moved, Some(location));
+        }
+        self.set_location(entity_id, None);
+        self.allocator.free(*entity_id);
     }
 }
@@ -0,0 +1,166 @@
+use crate::{
+    SystemWorldArgs,
+    resource::{Res, ResMut, Resource, ResourceManager},
+    system::{ReadOnlySystemParam, SafetyInfo, SystemParam, SystemRunArgs},
+};
+
+/// A message systems can send to each other through `EventWriter` and `EventReader`
+pub trait Event: Send + Sync + 'static {}
+
+#[derive(Debug)]
+struct EventInstance<E> {
+    id: usize,
+    event: E,
+}
+
+/// Stores the events of type `E` sent during the last two updates. Added to the world as a
+/// resource by `World::add_event`.
+#[derive(Debug)]
+pub struct Events<E: Event> {
+    /// The events sent during the previous update
+    previous: Vec<EventInstance<E>>,
+    /// The events sent during the current update
+    current: Vec<EventInstance<E>>,
+    next_id: usize,
+}
+
+impl<E: Event> Resource for Events<E> {}
+
+impl<E: Event> Default for Events<E> {
+    fn default() -> Self {
+        Self {
+            previous: Vec::new(),
+            current: Vec::new(),
+            next_id: 0,
+        }
+    }
+}
+
+impl<E: Event> Events<E> {
+    pub fn send(&mut self, event: E) {
+        self.current.push(EventInstance {
+            id: self.next_id,
+            event,
+        });
+        self.next_id += 1;
+    }
+
+    /// Drops the events of the previous update, so every event is kept for two updates
+    pub fn update(&mut self) {
+        self.previous = std::mem::take(&mut self.current);
+    }
+
+    /// Iterates over the events after `cursor`, oldest first, moving the cursor past them
+    pub(crate) fn read<'a>(
+        &'a self,
+        cursor: &mut usize,
+    ) -> impl Iterator<Item = &'a E> + use<'a, E> {
+        let cursor = std::mem::replace(cursor, self.next_id);
+        self.read_from(cursor)
+    }
+
+    /// The events with an id of at least `cursor`, oldest first
+    pub(crate) fn read_from(&self, cursor: usize) -> impl Iterator<Item = &E> {
+        self.previous
+            .iter()
+            .chain(&self.current)
+            .filter(move |instance| instance.id >= cursor)
+            .map(|instance| &instance.event)
+    }
+
+    pub(crate) fn update_resource(resources: &mut ResourceManager) {
+        if let Some(events) = resources.get_mut::<Self>() {
+            events.update();
+        }
+    }
+}
+
+/// Sends events of type `E`.
+/// # Panics
+/// The system panics when run if the event was not added with `World::add_event`
+pub struct EventWriter<'a, E: Event> {
+    events: ResMut<'a, Events<E>>,
+}
+
+impl<E: Event> EventWriter<'_, E> {
+    pub fn send(&mut self, event: E) {
+        self.events.send(event);
+    }
+
+    pub fn send_batch(&mut self, events: impl IntoIterator<Item = E>) {
+        for event in events {
+            self.events.send(event);
+        }
+    }
+}
+
+impl<E: Event> SystemParam for EventWriter<'_, E> {
+    type State = ();
+
+    fn init_state(_: &mut SystemWorldArgs) -> Self::State {}
+
+    /// SAFETY: Cannot coexist with another `EventReader` or `EventWriter` of the same event.
+    unsafe fn init(state: *mut Self::State, args: SystemRunArgs) -> Self {
+        Self {
+            events: unsafe { ResMut::init(state, args) },
+        }
+    }
+
+    fn safety_info(args: &mut SystemWorldArgs) -> Option<SafetyInfo> {
+        ResMut::<Events<E>>::safety_info(args)
+    }
+}
+
+/// Reads the events of type `E` sent since the system last read them, so every event is seen
+/// once by each system. Events are only kept for two updates, so a system that runs less often
+/// misses some.
+/// # Panics
+/// The system panics when run if the event was not added with `World::add_event`
+pub struct EventReader<'a, E: Event> {
+    /// The id of the first event this system has not read yet
+    cursor: &'a mut usize,
+    events: Res<'a, Events<E>>,
+}
+
+impl<E: Event> EventReader<'_, E> {
+    /// Iterates over the unread events, oldest first, marking them as read
+    pub fn read(&mut self) -> impl Iterator<Item = &E> {
+        self.events.read(self.cursor)
+    }
+
+    /// The number of unread events
+    pub fn len(&self) -> usize {
+        self.events.read_from(*self.cursor).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Marks every event as read
+    pub fn clear(&mut self) {
+        *self.cursor = self.events.next_id;
+    }
+}
+
+impl<E: Event> SystemParam for EventReader<'_, E> {
+    type State = usize;
+
+    fn init_state(_: &mut SystemWorldArgs) -> Self::State {
+        0
+    }
+
+    /// SAFETY: Cannot coexist with an `EventWriter` of the same event.
+    unsafe fn init(state: *mut Self::State, args: SystemRunArgs) -> Self {
+        Self {
+            cursor: unsafe { &mut *state },
+            events: unsafe { Res::init(&mut (), args) },
+        }
+    }
+
+    fn safety_info(args: &mut SystemWorldArgs) -> Option<SafetyInfo> {
+        Res::<Events<E>>::safety_info(args)
+    }
+}
+
+impl<E: Event> ReadOnlySystemParam for EventReader<'_, E> {}
@@ -0,0 +1,115 @@
+use crate::{
+    CommandError, Commands, Component, EntityCommands, World, component::ComponentBundle,
+    entity::EntityId,
+};
+
+/// The entity this entity is a child of. Added by `EntityCommands::add_child`. Removing it, or
+/// despawning the child, takes the child out of the `Children` of its parent.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+#[component(on_remove = remove_from_parent)]
+pub struct Parent(pub(crate) EntityId);
+
+impl Parent {
+    pub fn get(&self) -> EntityId {
+        self.0
+    }
+}
+
+/// The children of this entity, in the order they were added. Added by
+/// `EntityCommands::add_child`. Removing it, or despawning the parent, removes the `Parent` of
+/// every child, which are not despawned.
+#[derive(Component, Debug, Clone, Default, PartialEq, Eq)]
+#[component(on_remove = orphan_children)]
+pub struct Children(pub(crate) Vec<EntityId>);
+
+impl Children {
+    pub fn iter(&self) -> impl Iterator<Item = EntityId> + '_ {
+        self.0.iter().copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+fn remove_from_parent(world: &mut World, child: EntityId) {
+    let Some(&Parent(parent)) = world.get::<Parent>(child) else {
+        return;
+    };
+    if let Some(mut children) = world.get_mut::<Children>(parent) {
+        children.0.retain(|&sibling| sibling != child);
+    }
+}
+
+fn orphan_children(world: &mut World, parent: EntityId) {
+    let Some(Children(children)) = world.get::<Children>(parent).cloned() else {
+        return;
+    };
+    for child in children {
+        if world.get::<Parent>(child) == Some(&Parent(parent)) {
+            world.remove::<(Parent,)>(child);
+        }
+    }
+}
+
+/// Makes `child` a child of `parent`, taking it away from its previous parent if it had one.
+/// Fails if the child is the parent or one of its ancestors.
+pub(crate) fn add_child(
+    world: &mut World,
+    parent: EntityId,
+    child: EntityId,
+) -> Result<(), CommandError> {
+    for entity in [parent, child] {
+        if !world.contains(entity) {
+            return Err(CommandError::EntityNotFound(entity));
+        }
+    }
+    let mut ancestor = Some(parent);
+    while let Some(entity) = ancestor {
+        if entity == child {
+            return Err(CommandError::HierarchyCycle { parent, child });
+        }
+        ancestor = world.get::<Parent>(entity).map(Parent::get);
+    }
+    if let Some(&Parent(previous)) = world.get::<Parent>(child) {
+        if previous == parent {
+            return Ok(());
+        }
+        if let Some(mut children) = world.get_mut::<Children>(previous) {
+            children.0.retain(|&sibling| sibling != child);
+        }
+    }
+    world.insert(child, (Parent(parent),));
+    match world.get_mut::<Children>(parent) {
+        Some(mut children) => children.0.push(child),
+        None => world.insert(parent, (Children(vec![child]),)),
+    }
+    Ok(())
+}
+
+/// Spawns the children of an entity, given to `EntityCommands::with_children`
+pub struct ChildBuilder<'a> {
+    commands: &'a mut Commands,
+    parent: EntityId,
+}
+
+impl<'a> ChildBuilder<'a> {
+    pub(crate) fn new(commands: &'a mut Commands, parent: EntityId) -> Self {
+        Self { commands, parent }
+    }
+
+    /// Spawns an entity as a child of the parent
+    pub fn spawn(&mut self, components: impl ComponentBundle) -> EntityCommands<'_> {
+        let child = self.commands.spawn(components).id();
+        self.commands.entity(self.parent).add_child(child);
+        self.commands.entity(child)
+    }
+
+    pub fn parent_entity(&self) -> EntityId {
+        self.parent
+    }
+}
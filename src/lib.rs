@@ -1,50 +1,123 @@
-use component::{ComponentBundle, ComponentManager};
-use entity::{EntityId, EntityManager};
-use system::{IntoSystem, SafetyInfo, SystemParam, SystemParamError};
+use std::{collections::HashMap, marker::PhantomData, time::Duration};
 
-pub use crate::component::Component;
-pub use crate::query::Query;
-pub use tinysimpleecs_rust_macros::Component;
+use component::ComponentBundle;
+use entity::{EntityAllocator, EntityManager};
+use system::{IntoSystem, SafetyInfo, SystemParam, SystemParamError, SystemsManager};
 
+pub use crate::change_detection::Mut;
+pub use crate::command::{Command, CommandError, CommandErrorPolicy, IntoCommandResult};
+pub use crate::component::{Component, ComponentHook, ComponentHooks};
+pub use crate::entity::EntityId;
+pub use crate::event::{Event, EventReader, EventWriter, Events};
+pub use crate::hierarchy::{ChildBuilder, Children, Parent};
+pub use crate::observer::{IntoObserver, Trigger};
+pub use crate::query::{Added, Changed, Query, QueryEntityError, QueryIter, QueryResult};
+pub use crate::removal_detection::RemovedComponents;
+pub use crate::resource::{Res, ResMut, Resource};
+pub use crate::schedule::{
+    ApplyDeferred, ExecutorKind, IntoSystemConfig, ScheduleBuildError, ScheduleLabel, SystemConfig,
+    SystemLabel,
+};
+pub use crate::system::IntoCondition;
+pub use tinysimpleecs_rust_macros::{Component, Event, Resource};
+
+// Lets the derive macros name the crate by its path inside the crate too
+extern crate self as tinysimpleecs_rust;
+
+mod change_detection;
+mod command;
 mod component;
 mod entity;
+mod event;
+mod hierarchy;
+mod observer;
 mod query;
+mod removal_detection;
+mod resource;
+mod schedule;
 mod system;
 
-#[derive(Default)]
 pub struct World {
     components_manager: component::ComponentManager,
     entity_manager: entity::EntityManager,
-    systems_manager: system::SystemsManager,
+    resources: resource::ResourceManager,
+    schedules: HashMap<ScheduleLabel, SystemsManager>,
+    /// The schedules being run, which are taken out of `schedules` meanwhile
+    running_schedules: Vec<ScheduleLabel>,
+    observers: observer::Observers,
     commands: Commands,
+    command_error_policy: CommandErrorPolicy,
+    /// The errors of failed commands, kept when the policy is `CommandErrorPolicy::Collect`
+    command_errors: Vec<CommandError>,
+    /// Swaps the buffers of every event type added to the world
+    event_updaters: Vec<fn(&mut resource::ResourceManager)>,
+    has_started: bool,
+    /// The schedules `World::update` runs after `Startup`, in order
+    update_order: Vec<ScheduleLabel>,
+    fixed_timestep: Duration,
+    /// The most `FixedUpdate` runs a single update catches up with
+    max_fixed_steps: u32,
+    /// Time passed that has not been consumed by a `FixedUpdate` run yet
+    fixed_time_accumulated: Duration,
+}
+
+impl Default for World {
+    fn default() -> Self {
+        let entity_manager = EntityManager::default();
+        // Commands reserve ids from the same allocator the entity manager frees them into
+        let commands = Commands::new(entity_manager.allocator());
+        Self {
+            components_manager: Default::default(),
+            entity_manager,
+            resources: Default::default(),
+            schedules: Default::default(),
+            running_schedules: Vec::new(),
+            observers: Default::default(),
+            commands,
+            command_error_policy: CommandErrorPolicy::default(),
+            command_errors: Vec::new(),
+            event_updaters: Vec::new(),
+            has_started: false,
+            update_order: vec![
+                ScheduleLabel::PreUpdate,
+                ScheduleLabel::FixedUpdate,
+                ScheduleLabel::Update,
+                ScheduleLabel::PostUpdate,
+            ],
+            fixed_timestep: Duration::from_secs(1) / 60,
+            max_fixed_steps: 8,
+            fixed_time_accumulated: Duration::ZERO,
+        }
+    }
 }
 
+/// The parts of the world systems are set up with, before they run
 pub struct SystemWorldArgs<'a> {
     pub(crate) components_manager: &'a mut component::ComponentManager,
     pub(crate) entity_manager: &'a mut entity::EntityManager,
-    pub(crate) commands: &'a mut Commands,
+    pub(crate) resources: &'a mut resource::ResourceManager,
 }
 
 impl<'a> SystemWorldArgs<'a> {
     pub(crate) fn new(
         components_manager: &'a mut component::ComponentManager,
         entity_manager: &'a mut entity::EntityManager,
-        commands: &'a mut Commands,
+        resources: &'a mut resource::ResourceManager,
     ) -> Self {
         Self {
             components_manager,
             entity_manager,
-            commands,
+            resources,
         }
     }
 
-    #[cfg(test)]
-    pub(crate) fn from_world(world: &'a mut World) -> Self {
-        Self::new(
-            &mut world.components_manager,
-            &mut world.entity_manager,
-            &mut world.commands,
-        )
+    /// The pointers systems run with. The arguments must not be used while a system runs.
+    pub(crate) fn ptrs(&mut self) -> system::WorldPtrs {
+        system::WorldPtrs {
+            components_manager: self.components_manager,
+            entity_manager: self.entity_manager,
+            resources: self.resources,
+        }
     }
 }
 
@@ -55,59 +128,451 @@ impl World {
 
     #[cfg(test)]
     pub(crate) fn spawn(&mut self, components: impl ComponentBundle + 'static) -> EntityId {
-        let id = self.commands.spawn(components);
-        self.commands
-            .apply(&mut self.entity_manager, &mut self.components_manager);
+        let id = self.commands.spawn(components).id();
+        self.apply_commands();
         id
     }
 
-    // #[cfg(test)]
-    // pub(crate) fn despawn(&mut self, entity: &entity::EntityId) {
-    //     self.entity_manager.despawn(entity);
-    // }
+    /// Spawns an entity with an id reserved by `Commands`, running the hooks of its components
+    pub(crate) fn spawn_reserved(&mut self, entity: EntityId, components: impl ComponentBundle) {
+        self.entity_manager
+            .spawn(entity, components, &mut self.components_manager);
+        let bitmask = self.entity_manager.bitmask(&entity).unwrap();
+        if !self.components_manager.has_hooks(bitmask) {
+            return;
+        }
+        let added = bitmask.clone();
+        self.run_hooks(entity, &added, |hooks| hooks.on_add);
+        self.run_hooks(entity, &added, |hooks| hooks.on_insert);
+    }
+
+    /// Despawns the entity after running the `on_remove` hooks of its components
+    pub(crate) fn despawn(&mut self, entity: &EntityId) {
+        let bitmask = self
+            .entity_manager
+            .bitmask(entity)
+            .expect("Attempted to despawn non-existent entity!");
+        if self.components_manager.has_hooks(bitmask) {
+            let removed = bitmask.clone();
+            self.run_hooks(*entity, &removed, |hooks| hooks.on_remove);
+            // A hook may have despawned the entity already
+            if !self.contains(*entity) {
+                return;
+            }
+        }
+        self.entity_manager.despawn(entity);
+    }
+
+    /// Runs the chosen hook of every component, until the entity is despawned by one of them
+    fn run_hooks(
+        &mut self,
+        entity: EntityId,
+        components: &entity::EntityBitmask,
+        hook: fn(ComponentHooks) -> Option<ComponentHook>,
+    ) {
+        if !self.components_manager.has_hooks(components) {
+            return;
+        }
+        for component in components.iter() {
+            if !self.contains(entity) {
+                return;
+            }
+            if let Some(hook) = hook(self.components_manager.hooks(component)) {
+                hook(self, entity);
+            }
+        }
+    }
+
+    /// Returns the hooks of the component so they can be set
+    pub fn register_component_hooks<C: Component>(&mut self) -> &mut ComponentHooks {
+        let component = self
+            .components_manager
+            .register_component_if_not_exists::<C>();
+        self.components_manager.hooks_mut(component)
+    }
+
+    /// Applies the queued commands in the order they were queued. Commands queued while
+    /// applying them, like the ones of observers, are applied right after the command that caused
+    /// them. Failed commands are handled according to the command error policy.
+    pub(crate) fn apply_commands(&mut self) {
+        while !self.commands.actions_queue.is_empty() {
+            for action in std::mem::take(&mut self.commands.actions_queue) {
+                if let Err(error) = action(self) {
+                    self.handle_command_error(error);
+                }
+            }
+        }
+    }
+
+    fn handle_command_error(&mut self, error: CommandError) {
+        match self.command_error_policy {
+            CommandErrorPolicy::Panic => panic!("Failed to apply a command: {error}"),
+            CommandErrorPolicy::Log => log::error!("Failed to apply a command: {error}"),
+            CommandErrorPolicy::Ignore => {}
+            CommandErrorPolicy::Collect => self.command_errors.push(error),
+        }
+    }
+
+    /// Chooses what happens when a command fails. Defaults to panicking.
+    pub fn set_command_error_policy(&mut self, policy: CommandErrorPolicy) {
+        self.command_error_policy = policy;
+    }
+
+    /// The errors of the commands that failed since the errors were last taken, oldest first.
+    /// Only collected with `CommandErrorPolicy::Collect`.
+    pub fn command_errors(&self) -> &[CommandError] {
+        &self.command_errors
+    }
+
+    pub fn take_command_errors(&mut self) -> Vec<CommandError> {
+        std::mem::take(&mut self.command_errors)
+    }
+
+    /// Whether `entity` is currently alive. Stale ids of despawned entities are never contained,
+    /// even if their index has been reused.
+    pub fn contains(&self, entity: EntityId) -> bool {
+        self.entity_manager.contains(&entity)
+    }
+
+    /// The component of the entity, or `None` if either does not exist
+    pub fn get<C: Component>(&self, entity: EntityId) -> Option<&C> {
+        let component = self.components_manager.get_component_id::<C>()?;
+        self.entity_manager.get(&entity, component)
+    }
+
+    /// Mutable access to the component of the entity, marking it as changed when written to.
+    /// Returns `None` if either does not exist.
+    pub fn get_mut<C: Component>(&mut self, entity: EntityId) -> Option<Mut<'_, C>> {
+        let component = self.components_manager.get_component_id::<C>()?;
+        let ticks = change_detection::SystemTicks {
+            last_run: 0,
+            this_run: self.entity_manager.increment_change_tick(),
+        };
+        let (value, component_ticks) = self.entity_manager.get_mut(&entity, component)?;
+        Some(Mut::new(value, component_ticks, ticks))
+    }
+
+    /// Adds the components to `entity` right away, overwriting the ones it already has, then
+    /// runs their hooks.
+    /// # Panics
+    /// If the entity does not exist
+    pub fn insert<B: ComponentBundle>(&mut self, entity: EntityId, components: B) {
+        let edge = self
+            .entity_manager
+            .insert(&entity, components, &mut self.components_manager);
+        self.run_hooks(entity, &edge.added, |hooks| hooks.on_add);
+        self.run_hooks(entity, &edge.inserted, |hooks| hooks.on_insert);
+    }
+
+    /// Runs the `on_remove` hooks of the components of the bundle `B` that `entity` has, then
+    /// removes them right away.
+    /// # Panics
+    /// If the entity does not exist
+    pub fn remove<B: ComponentBundle>(&mut self, entity: EntityId) {
+        let previous = self
+            .entity_manager
+            .bitmask(&entity)
+            .expect("Attempted to remove components from non-existent entity!");
+        let bundle = B::bitmask(&mut self.components_manager);
+        if self.components_manager.has_hooks(&bundle) {
+            let removed = bundle.intersection(previous).collect();
+            self.run_hooks(entity, &removed, |hooks| hooks.on_remove);
+            // A hook may have despawned the entity
+            if !self.contains(entity) {
+                return;
+            }
+        }
+        self.entity_manager
+            .remove::<B>(&entity, &mut self.components_manager);
+    }
+
+    /// Inserts the resource, returning the previous value if there was one
+    pub fn insert_resource<R: Resource>(&mut self, resource: R) -> Option<R> {
+        self.resources.insert(resource)
+    }
+
+    pub fn resource<R: Resource>(&self) -> Option<&R> {
+        self.resources.get::<R>()
+    }
+
+    pub fn resource_mut<R: Resource>(&mut self) -> Option<&mut R> {
+        self.resources.get_mut::<R>()
+    }
 
-    pub fn add_system<T>(&mut self, system: impl IntoSystem<T>) -> Result<(), SystemParamError> {
+    pub fn remove_resource<R: Resource>(&mut self) -> Option<R> {
+        self.resources.remove::<R>()
+    }
+
+    /// Adds the `Events<E>` resource that `EventWriter<E>` and `EventReader<E>` use, if it does
+    /// not exist yet. Its events are cleaned up by `World::update`.
+    pub fn add_event<E: Event>(&mut self) {
+        if self.resources.get::<Events<E>>().is_none() {
+            self.resources.insert(Events::<E>::default());
+            self.event_updaters.push(Events::<E>::update_resource);
+        }
+    }
+
+    /// # Panics
+    /// If the event was not added with `World::add_event`
+    pub fn send_event<E: Event>(&mut self, event: E) {
+        self.resources
+            .get_mut::<Events<E>>()
+            .expect("The event was not added to the world")
+            .send(event);
+    }
+
+    /// Adds an observer, which runs whenever `E` is triggered, with or without targets
+    pub fn observe<E: Event, T>(
+        &mut self,
+        observer: impl IntoObserver<E, T>,
+    ) -> Result<(), SystemParamError> {
+        self.add_observer(observer, None)
+    }
+
+    /// Adds an observer that only runs when `E` is triggered on `entity`
+    pub fn observe_entity<E: Event, T>(
+        &mut self,
+        entity: EntityId,
+        observer: impl IntoObserver<E, T>,
+    ) -> Result<(), SystemParamError> {
+        self.add_observer(observer, Some(entity))
+    }
+
+    fn add_observer<E: Event, T>(
+        &mut self,
+        observer: impl IntoObserver<E, T>,
+        entity: Option<EntityId>,
+    ) -> Result<(), SystemParamError> {
+        let mut args = SystemWorldArgs::new(
+            &mut self.components_manager,
+            &mut self.entity_manager,
+            &mut self.resources,
+        );
+        let observer = observer.parse(&mut args)?;
+        self.observers.add(observer, entity);
+        Ok(())
+    }
+
+    /// Runs the observers of `E` that watch every trigger right away, then applies the commands
+    /// they queued
+    pub fn trigger<E: Event>(&mut self, event: E) {
+        self.run_observers(&event, None);
+        self.apply_commands();
+    }
+
+    /// Runs the observers of `E` once for every target, along with the observers watching that
+    /// target, then applies the commands they queued
+    pub fn trigger_targets<E: Event>(
+        &mut self,
+        event: E,
+        targets: impl IntoIterator<Item = EntityId>,
+    ) {
+        for target in targets {
+            self.run_observers(&event, Some(target));
+        }
+        self.apply_commands();
+    }
+
+    fn run_observers<E: Event>(&mut self, event: &E, target: Option<EntityId>) {
+        let mut observers = self.observers.take::<E>();
+        for observer in &mut observers {
+            observer.run(Trigger::new(event, target), self);
+        }
+        self.observers.restore(observers);
+    }
+
+    /// Adds a system to the `Update` schedule, along with its labels and ordering constraints if
+    /// it has any
+    pub fn add_system<T, S: IntoSystem<T>>(
+        &mut self,
+        system: impl IntoSystemConfig<T, S>,
+    ) -> Result<(), SystemParamError> {
+        self.add_system_to(ScheduleLabel::Update, system)
+    }
+
+    /// Adds a system to the schedule, creating the schedule if it does not exist yet
+    pub fn add_system_to<T, S: IntoSystem<T>>(
+        &mut self,
+        schedule: ScheduleLabel,
+        system: impl IntoSystemConfig<T, S>,
+    ) -> Result<(), SystemParamError> {
         let args = SystemWorldArgs::new(
             &mut self.components_manager,
             &mut self.entity_manager,
-            &mut self.commands,
+            &mut self.resources,
         );
-        self.systems_manager.add_system(args, system)?;
+        self.schedules
+            .entry(schedule)
+            .or_default()
+            .add_system(args, system)?;
         Ok(())
     }
 
     /// # Safety
     /// Funky things might happen if you call it, specifically multiple mutable references to the
     /// same value. However, it might be good if the safety checks are too restraining
-    pub unsafe fn add_system_unchecked<T>(&mut self, system: impl IntoSystem<T>) {
-        unsafe { self.systems_manager.add_system_unchecked(system) };
+    pub unsafe fn add_system_unchecked<T, S: IntoSystem<T>>(
+        &mut self,
+        system: impl IntoSystemConfig<T, S>,
+    ) {
+        unsafe { self.add_system_to_unchecked(ScheduleLabel::Update, system) };
+    }
+
+    /// # Safety
+    /// See `World::add_system_unchecked`
+    pub unsafe fn add_system_to_unchecked<T, S: IntoSystem<T>>(
+        &mut self,
+        schedule: ScheduleLabel,
+        system: impl IntoSystemConfig<T, S>,
+    ) {
+        let systems_manager = self.schedules.entry(schedule).or_default();
+        unsafe { systems_manager.add_system_unchecked(system) };
+    }
+
+    /// Sorts the systems of the schedule according to their ordering constraints. This happens
+    /// automatically before running them, but calling it lets a conflict be handled instead of
+    /// panicking.
+    pub fn build_schedule(&mut self, schedule: ScheduleLabel) -> Result<(), ScheduleBuildError> {
+        match self.schedules.get_mut(&schedule) {
+            Some(systems_manager) => systems_manager.build(),
+            None => Ok(()),
+        }
+    }
+
+    /// Runs every system of the schedule, applying the commands they queued at every sync point
+    /// and once they all ran. Every system queues into its own buffer, and the buffers are
+    /// applied in the order the systems appear in the schedule, whichever order they ran in.
+    /// Running a schedule without systems does nothing. The commands of a schedule's systems can
+    /// run other schedules, but not the one they were queued from.
+    /// # Panics
+    /// If the ordering constraints of the systems form a cycle, or if the schedule is already
+    /// running
+    pub fn run_schedule(&mut self, schedule: ScheduleLabel) {
+        assert!(
+            !self.running_schedules.contains(&schedule),
+            "The schedule {schedule:?} was run again while it was already running"
+        );
+        // Taken out so the commands can be applied to the world between its systems
+        let Some(mut systems_manager) = self.schedules.remove(&schedule) else {
+            return;
+        };
+        self.running_schedules.push(schedule);
+        systems_manager.run_all(self);
+        self.running_schedules.pop();
+        // Commands may have added systems to the schedule while it was taken out
+        if let Some(added) = self.schedules.remove(&schedule) {
+            systems_manager.append(added);
+        }
+        self.schedules.insert(schedule, systems_manager);
     }
 
+    /// Runs the `Update` schedule
+    /// # Panics
+    /// If the ordering constraints of the systems form a cycle
     pub fn run_all_systems(&mut self) {
-        let args = SystemWorldArgs::new(
-            &mut self.components_manager,
-            &mut self.entity_manager,
-            &mut self.commands,
+        self.run_schedule(ScheduleLabel::Update);
+    }
+
+    /// Chooses how the systems of the schedule are run, creating the schedule if it does not
+    /// exist yet. Schedules run their systems in parallel by default.
+    pub fn set_executor(&mut self, schedule: ScheduleLabel, executor: ExecutorKind) {
+        self.schedules.entry(schedule).or_default().executor = Some(executor);
+    }
+
+    /// Sets how much time has to pass between `FixedUpdate` runs. Defaults to 1/60th of a second.
+    pub fn set_fixed_timestep(&mut self, timestep: Duration) {
+        assert!(!timestep.is_zero(), "The fixed timestep must not be zero");
+        self.fixed_timestep = timestep;
+    }
+
+    /// Sets how many times `FixedUpdate` can run during a single update. When more fixed
+    /// timesteps passed, the ones left are skipped so a slow frame does not make the next ones
+    /// slower. Defaults to 8.
+    pub fn set_max_fixed_steps(&mut self, steps: u32) {
+        assert!(
+            steps > 0,
+            "FixedUpdate must be allowed to run at least once per update"
+        );
+        self.max_fixed_steps = steps;
+    }
+
+    /// Makes `World::update` run the schedule right after `after`, which must already be run
+    /// by it. `Startup` cannot be given, since it only runs once.
+    /// # Panics
+    /// If `after` is not run by `World::update`, or `schedule` already is
+    pub fn insert_schedule_after(&mut self, schedule: ScheduleLabel, after: ScheduleLabel) {
+        assert!(
+            !self.update_order.contains(&schedule),
+            "The schedule {schedule:?} is already run by World::update"
         );
-        self.systems_manager.run_all(args);
+        let Some(position) = self.update_order.iter().position(|&label| label == after) else {
+            panic!("The schedule {after:?} is not run by World::update");
+        };
+        self.update_order.insert(position + 1, schedule);
+    }
+
+    /// Advances the world by one frame that took `delta`. Drops the events sent and the
+    /// component removals recorded two updates ago, then runs `Startup` if this is the first
+    /// update, `PreUpdate`, `FixedUpdate` once for every fixed timestep that fit in the time
+    /// passed so far, `Update` and `PostUpdate`, along with the schedules inserted with
+    /// `World::insert_schedule_after`.
+    /// # Panics
+    /// If the ordering constraints of the systems of a schedule form a cycle
+    pub fn update(&mut self, delta: Duration) {
+        for update_events in &self.event_updaters {
+            update_events(&mut self.resources);
+        }
+        self.entity_manager.update_removed();
+        if !self.has_started {
+            self.has_started = true;
+            self.run_schedule(ScheduleLabel::Startup);
+        }
+        self.fixed_time_accumulated += delta;
+
+        // Indexed since commands may insert schedules while they run
+        let mut position = 0;
+        while let Some(&schedule) = self.update_order.get(position) {
+            match schedule {
+                ScheduleLabel::FixedUpdate => self.run_fixed_update(),
+                _ => self.run_schedule(schedule),
+            }
+            position += 1;
+        }
+    }
+
+    /// Runs `FixedUpdate` once for every fixed timestep accumulated, up to the maximum number of
+    /// steps
+    fn run_fixed_update(&mut self) {
+        let mut steps = 0;
+        while self.fixed_time_accumulated >= self.fixed_timestep {
+            if steps == self.max_fixed_steps {
+                let left = self.fixed_time_accumulated.as_nanos() % self.fixed_timestep.as_nanos();
+                self.fixed_time_accumulated = Duration::from_nanos(left as u64);
+                break;
+            }
+            self.fixed_time_accumulated -= self.fixed_timestep;
+            self.run_schedule(ScheduleLabel::FixedUpdate);
+            steps += 1;
+        }
     }
 }
 
-type CommandAction = Vec<Box<dyn FnOnce(&mut EntityManager, &mut ComponentManager)>>;
+type CommandAction = Vec<Box<dyn FnOnce(&mut World) -> Result<(), CommandError> + Send>>;
 
-#[derive(Default)]
 pub struct Commands {
     actions_queue: CommandAction,
-    next_id: usize,
+    allocator: EntityAllocator,
 }
 
 impl SystemParam for &mut Commands {
-    /// SAFETY: Only one commands per system
-    unsafe fn init(args: *mut SystemWorldArgs) -> Self {
-        // What... The hell am I doing.
-        // This is safe though, since args will always outlive
-        // this reference, so I guess it's fine
-        unsafe { &mut *((*args).commands as *mut Commands) }
+    type State = ();
+
+    fn init_state(_: &mut SystemWorldArgs) -> Self::State {}
+
+    /// SAFETY: Only one commands per system. `args.commands` is the buffer of the system being run.
+    unsafe fn init(_: *mut Self::State, args: system::SystemRunArgs) -> Self {
+        // The buffer lives in the system, which outlives its parameters
+        unsafe { &mut *args.commands }
     }
 
     fn safety_info(_: &mut SystemWorldArgs) -> Option<SafetyInfo> {
@@ -116,48 +581,175 @@ impl SystemParam for &mut Commands {
 }
 
 impl Commands {
+    pub(crate) fn new(allocator: EntityAllocator) -> Self {
+        Self {
+            actions_queue: Default::default(),
+            allocator,
+        }
+    }
+
+    /// Queues a command, applied after the commands queued before it. If it fails, the error is
+    /// handled according to the world's `CommandErrorPolicy`.
+    pub fn add(&mut self, command: impl Command) {
+        self.actions_queue
+            .push(Box::new(move |world: &mut World| command.apply(world)));
+    }
+
+    /// Moves the commands of `other` to the end of the queue
+    pub(crate) fn append(&mut self, other: &mut Commands) {
+        self.actions_queue.append(&mut other.actions_queue);
+    }
+
     fn new_entity_id(&mut self) -> EntityId {
-        let new_entity = EntityId::new(self.next_id);
-        self.next_id += 1;
-        new_entity
+        self.allocator.alloc()
     }
 
-    pub fn spawn(&mut self, tospawn: impl ComponentBundle + 'static) -> EntityId {
+    /// Reserves an id for a new entity with the components, which is spawned when the commands
+    /// are applied. The returned `EntityCommands` queue more commands for it.
+    pub fn spawn(&mut self, tospawn: impl ComponentBundle + 'static) -> EntityCommands<'_> {
         let id = self.new_entity_id();
-        self.actions_queue.push(Box::new(
-            move |entity_manager: &mut EntityManager, components_manager: &mut ComponentManager| {
-                entity_manager.spawn(id, tospawn, components_manager);
-            },
-        ));
-        id
+        self.add(command::Spawn {
+            entity: id,
+            components: tospawn,
+        });
+        self.entity(id)
+    }
+
+    /// Queues commands for an existing entity. They fail if the entity does not exist by then,
+    /// which is handled according to the `CommandErrorPolicy` set with
+    /// `World::set_command_error_policy`.
+    pub fn entity(&mut self, entity: EntityId) -> EntityCommands<'_> {
+        EntityCommands {
+            entity,
+            commands: self,
+        }
     }
+
+    /// Despawns the entity. Fails if it does not exist by then.
     pub fn despawn(&mut self, todespawn: EntityId) {
-        self.actions_queue.push(Box::new(
-            move |entity_manager: &mut EntityManager, _: &mut ComponentManager| {
-                entity_manager.despawn(&todespawn);
-            },
-        ));
+        self.add(command::Despawn {
+            entity: todespawn,
+            strict: true,
+        });
+    }
+
+    /// Despawns the entity if it still exists, for when something else may despawn it first
+    pub fn try_despawn(&mut self, todespawn: EntityId) {
+        self.add(command::Despawn {
+            entity: todespawn,
+            strict: false,
+        });
+    }
+
+    /// Adds the components to `entity`, overwriting the ones it already has
+    pub fn insert(&mut self, entity: EntityId, components: impl ComponentBundle + 'static) {
+        self.add(command::Insert { entity, components });
+    }
+
+    /// Removes the components of the bundle `B` from `entity`
+    pub fn remove<B: ComponentBundle + 'static>(&mut self, entity: EntityId) {
+        self.add(command::Remove::<B> {
+            entity,
+            _marker: PhantomData,
+        });
+    }
+
+    /// Runs the observers of `E` when the commands are applied
+    pub fn trigger<E: Event>(&mut self, event: E) {
+        self.add(move |world: &mut World| world.trigger(event));
     }
 
-    pub(crate) fn apply(
+    /// Runs the observers of `E` for every target when the commands are applied
+    pub fn trigger_targets<E: Event>(
         &mut self,
-        entity_manager: &mut EntityManager,
-        components_manager: &mut ComponentManager,
+        event: E,
+        targets: impl IntoIterator<Item = EntityId>,
     ) {
-        while let Some(action) = self.actions_queue.pop() {
-            action(entity_manager, components_manager);
-        }
+        let targets: Vec<_> = targets.into_iter().collect();
+        self.add(move |world: &mut World| world.trigger_targets(event, targets));
+    }
+}
+
+/// Queues commands for a single entity into the commands it was created from
+pub struct EntityCommands<'a> {
+    entity: EntityId,
+    commands: &'a mut Commands,
+}
+
+impl EntityCommands<'_> {
+    pub fn id(&self) -> EntityId {
+        self.entity
+    }
+
+    /// Adds the components to the entity, overwriting the ones it already has
+    pub fn insert(&mut self, components: impl ComponentBundle + 'static) -> &mut Self {
+        self.commands.insert(self.entity, components);
+        self
+    }
+
+    /// Removes the components of the bundle `B` from the entity
+    pub fn remove<B: ComponentBundle + 'static>(&mut self) -> &mut Self {
+        self.commands.remove::<B>(self.entity);
+        self
+    }
+
+    /// Despawns the entity. Fails if it does not exist by then.
+    pub fn despawn(&mut self) {
+        self.commands.despawn(self.entity);
+    }
+
+    /// Despawns the entity if it still exists
+    pub fn try_despawn(&mut self) {
+        self.commands.try_despawn(self.entity);
+    }
+
+    /// Makes `child` a child of the entity, taking it away from its previous parent if it had
+    /// one. Both entities get a `Parent` or `Children` component. Fails if either does not exist
+    /// by then, or with `CommandError::HierarchyCycle` if the child is the entity or one of its
+    /// ancestors.
+    pub fn add_child(&mut self, child: EntityId) -> &mut Self {
+        let parent = self.entity;
+        self.add(move |_, world| hierarchy::add_child(world, parent, child))
+    }
+
+    /// Spawns children of the entity through the `ChildBuilder`
+    pub fn with_children(&mut self, spawn_children: impl FnOnce(&mut ChildBuilder)) -> &mut Self {
+        spawn_children(&mut ChildBuilder::new(self.commands, self.entity));
+        self
+    }
+
+    /// Queues a custom command run with the entity and the world. Like `Commands::add`, it can
+    /// return a `Result`.
+    pub fn add<Out: IntoCommandResult>(
+        &mut self,
+        command: impl FnOnce(EntityId, &mut World) -> Out + Send + 'static,
+    ) -> &mut Self {
+        let entity = self.entity;
+        self.commands
+            .add(move |world: &mut World| command(entity, world));
+        self
+    }
+
+    /// The commands the entity's commands are queued into
+    pub fn commands(&mut self) -> &mut Commands {
+        self.commands
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::query::{Query, QueryBundle};
-    use crate::system::SystemParam;
+    use crate::query::{Query, QueryBundle, QueryRestrictions, QueryState};
 
     use super::component::*;
     use super::*;
-    use tinysimpleecs_rust_macros::Component;
+    use std::{
+        any::TypeId,
+        sync::{
+            Arc, Condvar, Mutex,
+            atomic::{self, AtomicBool, AtomicUsize},
+        },
+    };
+    use tinysimpleecs_rust_macros::{Component, Event, Resource};
 
     #[derive(Component, Debug)]
     pub struct Banana;
@@ -165,11 +757,14 @@ mod tests {
     #[derive(Component, Debug)]
     pub struct Banana2(usize);
 
+    #[derive(Component, Debug)]
+    pub struct Banana3;
+
     #[test]
     fn manual_spawn_entity() {
         let mut world = World::new();
         let id = world.spawn((Banana {}, Banana2(23)));
-        assert!(world.entity_manager.entity_exists(&id));
+        assert!(world.contains(id));
         assert!(world.components_manager.component_exists::<Banana>());
         assert!(world.components_manager.component_exists::<Banana2>());
     }
@@ -199,6 +794,50 @@ mod tests {
     //     assert_eq!(info3.bitmask.0, BitSet::from_bytes(&[0b01000000]));
     // }
 
+    #[test]
+    fn despawned_index_is_reused_with_new_generation() {
+        let mut world = World::new();
+        let old = world.spawn((Banana {},));
+        world.despawn(&old);
+        let new = world.spawn((Banana2(1),));
+
+        assert_eq!(new.index(), old.index());
+        assert_eq!(new.generation(), old.generation() + 1);
+        assert!(!world.contains(old));
+        assert!(world.contains(new));
+    }
+
+    #[test]
+    fn despawn_keeps_locations_of_moved_entities() {
+        let mut world = World::new();
+        let ids: Vec<_> = (0..4).map(|i| world.spawn((Banana2(i),))).collect();
+        world.despawn(&ids[0]);
+        world.despawn(&ids[2]);
+
+        assert!(!world.contains(ids[0]));
+        assert!(world.contains(ids[1]));
+        assert!(!world.contains(ids[2]));
+        assert!(world.contains(ids[3]));
+
+        // Despawning the entities that were swapped into the freed rows must still work
+        world.despawn(&ids[3]);
+        world.despawn(&ids[1]);
+        assert!(!world.contains(ids[1]));
+        assert!(!world.contains(ids[3]));
+    }
+
+    #[test]
+    #[should_panic(expected = "Attempted to despawn non-existent entity!")]
+    fn despawn_stale_id_panics() {
+        let mut world = World::new();
+        let old = world.spawn((Banana {},));
+        world.despawn(&old);
+        let _ = world.spawn((Banana {},));
+
+        // `old` shares its index with the new entity but must not match it
+        world.despawn(&old);
+    }
+
     #[test]
     #[should_panic(expected = "duplicate component type in query")]
     fn test_query_with_duplicate_component_panics() {
@@ -206,8 +845,8 @@ mod tests {
         let _ = world.spawn((Banana {},));
 
         // This should panic due to repeated component type `Banana`
-        let _query: Query<(Banana, Banana), ()> =
-            unsafe { Query::init(&mut SystemWorldArgs::from_world(&mut world)) };
+        let mut state = None;
+        let _query: Query<(&Banana, &Banana), ()> = Query::from_world(&mut world, &mut state);
     }
 
     #[test]
@@ -219,16 +858,17 @@ mod tests {
         let _ = world.spawn((Banana {}, Banana {}));
     }
 
-    fn assert_within_query<V: QueryBundle, R: QueryBundle>(query: &Query<V, R>, id: usize) {
-        assert!(query.results.iter().any(|r| r.entity == EntityId::new(id)));
+    fn assert_within_query<V: QueryBundle, R: QueryRestrictions>(query: &Query<V, R>, id: usize) {
+        assert!(query.iter().any(|r| r.entity == EntityId::new(id, 0)));
     }
 
     macro_rules! assert_banana2_values {
         ($query:tt, $bananai:tt, [$($value:tt),+]) => {
             {
                 let mut values: Vec<usize> = vec![$($value),+];
-                assert_eq!($query.results.len(), values.len(), "values' ({:?}) len ({}) does not match result's ({:?}) len ({})", values, values.len(), $query.results, $query.results.len());
-                for r in $query.results.iter() {
+                let results: Vec<_> = $query.iter().collect();
+                assert_eq!(results.len(), values.len(), "values' ({:?}) len ({}) does not match result's ({:?}) len ({})", values, values.len(), results, results.len());
+                for r in results.iter() {
                     let index = values.iter().position(|n| *n == r.components.$bananai.0);
                     assert!(index.is_some(), "value: {:?} from {:?} not found in values: {:?}", r.components.$bananai.0, r, values);
                     values.swap_remove(index.unwrap());
@@ -248,40 +888,133 @@ mod tests {
     #[test]
     fn test_query_banana() {
         let mut world = dummy_world();
-        let query: Query<(Banana,), ()> =
-            unsafe { Query::init(&mut SystemWorldArgs::from_world(&mut world)) };
+        let mut state = None;
+        let query: Query<(&Banana,), ()> = Query::from_world(&mut world, &mut state);
         assert_within_query(&query, 0);
         assert_within_query(&query, 1);
-        assert_eq!(query.results.len(), 2);
+        assert_eq!(query.len(), 2);
     }
 
     #[test]
     fn test_query_banana2_and_modify() {
         let mut world = dummy_world();
-        let query: Query<(Banana2,), ()> =
-            unsafe { Query::init(&mut SystemWorldArgs::from_world(&mut world)) };
-        assert_eq!(query.results.len(), 2);
+        let mut state = None;
+        let mut query: Query<(&mut Banana2,), ()> = Query::from_world(&mut world, &mut state);
+        assert_eq!(query.len(), 2);
         assert_within_query(&query, 1);
         assert_within_query(&query, 2);
         assert_banana2_values!(query, 0, [24, 23]);
-        query.results[1].components.0.0 += 1;
+        query.results()[1].components.0.0 += 1;
+    }
+
+    #[test]
+    fn lazy_iteration_skips_empty_archetypes() {
+        let mut world = dummy_world();
+        let emptied = world.spawn((Banana2(1), Banana));
+        world.remove::<(Banana,)>(emptied);
+        world.despawn(&emptied);
+        let _ = world.spawn((Banana2(50),));
+
+        let mut state = None;
+        let mut query: Query<(&mut Banana2,), ()> = Query::from_world(&mut world, &mut state);
+        for mut result in query.iter_mut() {
+            result.components.0.0 *= 2;
+        }
+        assert_eq!(query.len(), 3);
+        assert_eq!(query.iter().count(), 3);
+        assert_banana2_values!(query, 0, [46, 48, 100]);
+    }
+
+    #[test]
+    fn query_state_only_checks_new_archetypes() {
+        let mut world = dummy_world();
+        let mut state = QueryState::new::<(&Banana,), ()>(&mut world.components_manager);
+        state.update_archetypes(&world.entity_manager, &world.components_manager);
+        assert_eq!(state.matched.len(), 2);
+
+        // Already matched archetypes are not matched again
+        state.update_archetypes(&world.entity_manager, &world.components_manager);
+        assert_eq!(state.matched.len(), 2);
+
+        let _ = world.spawn((Banana, Banana3));
+        let _ = world.spawn((Banana3,));
+        state.update_archetypes(&world.entity_manager, &world.components_manager);
+        assert_eq!(state.matched.len(), 3);
+    }
+
+    #[test]
+    fn system_query_sees_archetypes_created_between_runs() {
+        #[derive(Resource)]
+        struct Seen(Vec<usize>);
+
+        fn count(query: Query<(&Banana,), ()>, mut seen: ResMut<Seen>) {
+            seen.0.push(query.len());
+        }
+
+        let mut world = dummy_world();
+        world.insert_resource(Seen(Vec::new()));
+        world.add_system(count).unwrap();
+        world.run_all_systems();
+        let _ = world.spawn((Banana, Banana3));
+        world.run_all_systems();
+        assert_eq!(world.resource::<Seen>().unwrap().0, [2, 3]);
+    }
+
+    #[test]
+    fn query_random_access() {
+        let mut world = dummy_world();
+        let (a, b, c) = (
+            EntityId::new(0, 0),
+            EntityId::new(1, 0),
+            EntityId::new(2, 0),
+        );
+        let despawned = world.spawn((Banana2(0),));
+        world.despawn(&despawned);
+
+        let mut state = None;
+        let mut query: Query<(&mut Banana2,), ()> = Query::from_world(&mut world, &mut state);
+        assert_eq!(query.get(b).unwrap().0.0, 23);
+        query.get_mut(c).unwrap().0.0 += 1;
+        assert_eq!(query.get(c).unwrap().0.0, 25);
+        assert!(query.contains(b));
+        assert!(!query.contains(a));
+        assert_eq!(
+            query.get(a).unwrap_err(),
+            QueryEntityError::QueryDoesNotMatch(a)
+        );
+        assert_eq!(
+            query.get_mut(despawned).unwrap_err(),
+            QueryEntityError::NoSuchEntity(despawned)
+        );
+
+        let [(mut first,), (mut second,)] = query.get_many_mut([b, c]).unwrap();
+        std::mem::swap(&mut *first, &mut *second);
+        assert_eq!(query.get(b).unwrap().0.0, 25);
+        assert_eq!(
+            query.get_many_mut([b, c, b]).unwrap_err(),
+            QueryEntityError::AliasedMutability(b)
+        );
+        assert_eq!(
+            query.get_many_mut([b, a]).unwrap_err(),
+            QueryEntityError::QueryDoesNotMatch(a)
+        );
     }
 
     #[test]
     fn test_query_banana_and_banana2() {
         let mut world = dummy_world();
-        let query: Query<(Banana, Banana2), ()> =
-            unsafe { Query::init(&mut SystemWorldArgs::from_world(&mut world)) };
-        assert_eq!(query.results.len(), 1);
+        let mut state = None;
+        let query: Query<(&Banana, &Banana2), ()> = Query::from_world(&mut world, &mut state);
+        assert_eq!(query.len(), 1);
         assert_within_query(&query, 1);
     }
 
     #[test]
     fn test_query_banana_without_banana2() {
         let mut world = dummy_world();
-        let query: Query<(Banana,), (Banana2,)> =
-            unsafe { Query::init(&mut SystemWorldArgs::from_world(&mut world)) };
-        assert_eq!(query.results.len(), 1);
+        let mut state = None;
+        let query: Query<(&Banana,), (Banana2,)> = Query::from_world(&mut world, &mut state);
+        assert_eq!(query.len(), 1);
         assert_within_query(&query, 0);
     }
 
@@ -291,31 +1024,1332 @@ mod tests {
 
         // SAFETY: The two queries mustn't be alive at the same time
         {
-            let mut query: Query<(Banana2,), ()> =
-                unsafe { Query::init(&mut SystemWorldArgs::from_world(&mut world)) };
-            for result in &mut query.results {
+            let mut state = None;
+            let mut query: Query<(&mut Banana2,), ()> = Query::from_world(&mut world, &mut state);
+            for mut result in &mut query {
                 if result.components.0.0 == 24 {
                     result.components.0.0 += 1;
                 }
             }
         }
 
-        let query: Query<(Banana2,), ()> =
-            unsafe { Query::init(&mut SystemWorldArgs::from_world(&mut world)) };
+        let mut state = None;
+        let query: Query<(&mut Banana2,), ()> = Query::from_world(&mut world, &mut state);
         assert_within_query(&query, 1);
         assert_within_query(&query, 2);
         assert_banana2_values!(query, 0, [23, 25]);
     }
 
+    #[test]
+    fn insert_moves_entity_and_keeps_id() {
+        let mut world = dummy_world();
+        let id = EntityId::new(0, 0);
+        world.insert(id, (Banana2(7),));
+        assert!(world.contains(id));
+
+        let mut state = None;
+        let query: Query<(&Banana, &Banana2), ()> = Query::from_world(&mut world, &mut state);
+        assert_eq!(query.len(), 2);
+        assert_within_query(&query, 0);
+        assert_within_query(&query, 1);
+        assert_banana2_values!(query, 1, [7, 23]);
+    }
+
+    #[test]
+    fn insert_overwrites_existing_component() {
+        let mut world = dummy_world();
+        world.insert(EntityId::new(1, 0), (Banana2(40),));
+        world.insert(EntityId::new(2, 0), (Banana2(41), Banana));
+
+        let mut state = None;
+        let query: Query<(&mut Banana2,), ()> = Query::from_world(&mut world, &mut state);
+        assert_banana2_values!(query, 0, [40, 41]);
+    }
+
+    #[test]
+    fn cached_insert_edge_keeps_bundle_order() {
+        let mut world = dummy_world();
+        let other = world.spawn((Banana2(25),));
+        // The bundle is not in component id order, and the second insert reuses the edge
+        world.insert(EntityId::new(2, 0), (Banana3, Banana2(41), Banana));
+        world.insert(other, (Banana3, Banana2(42), Banana));
+
+        assert_eq!(world.get::<Banana2>(EntityId::new(2, 0)).unwrap().0, 41);
+        assert_eq!(world.get::<Banana2>(other).unwrap().0, 42);
+        assert!(world.get::<Banana3>(other).is_some());
+        assert!(world.get::<Banana>(other).is_some());
+    }
+
+    #[test]
+    fn remove_moves_entity_and_keeps_others() {
+        let mut world = dummy_world();
+        let extra = world.spawn((Banana, Banana2(30)));
+        world.remove::<(Banana,)>(EntityId::new(1, 0));
+        // Removing components the entity does not have is a no-op
+        world.remove::<(Banana,)>(EntityId::new(2, 0));
+
+        {
+            let mut state = None;
+            let query: Query<(&Banana2,), (Banana,)> = Query::from_world(&mut world, &mut state);
+            assert_within_query(&query, 1);
+            assert_within_query(&query, 2);
+            assert_banana2_values!(query, 0, [23, 24]);
+        }
+
+        // `extra` was swapped into the row freed by entity 1
+        world.remove::<(Banana, Banana2)>(extra);
+        assert!(world.contains(extra));
+        let mut state = None;
+        let query: Query<(&Banana,), ()> = Query::from_world(&mut world, &mut state);
+        assert_eq!(query.len(), 1);
+        assert_within_query(&query, 0);
+    }
+
+    #[test]
+    fn structural_changes_follow_cached_edges() {
+        let mut world = World::new();
+        let a = world.spawn((Banana2(1),));
+        let b = world.spawn((Banana2(2),));
+        world.insert(a, (Banana,));
+
+        let source = world.entity_manager.location(&b).unwrap().archetype;
+        let edge = world.entity_manager.archetypes[source].edges.insert[&TypeId::of::<(Banana,)>()]
+            .clone();
+        world.insert(b, (Banana,));
+        assert_eq!(
+            world.entity_manager.location(&a).unwrap().archetype,
+            edge.target
+        );
+        assert_eq!(
+            world.entity_manager.location(&b).unwrap().archetype,
+            edge.target
+        );
+        assert_eq!(
+            world.entity_manager.archetypes[source].edges.insert.len(),
+            1
+        );
+
+        world.remove::<(Banana,)>(a);
+        world.remove::<(Banana,)>(b);
+        let removal = &world.entity_manager.archetypes[edge.target].edges.remove;
+        assert_eq!(removal.len(), 1);
+        assert_eq!(removal[&TypeId::of::<(Banana,)>()].target, source);
+        assert_eq!(world.entity_manager.location(&b).unwrap().archetype, source);
+    }
+
+    #[test]
+    fn commands_insert_and_remove() {
+        let mut world = dummy_world();
+        let id = EntityId::new(2, 0);
+        world.commands.insert(id, (Banana,));
+        world.commands.remove::<(Banana2,)>(id);
+        world.apply_commands();
+
+        let mut state = None;
+        let query: Query<(&Banana,), (Banana2,)> = Query::from_world(&mut world, &mut state);
+        assert_eq!(query.len(), 2);
+        assert_within_query(&query, 0);
+        assert_within_query(&query, 2);
+    }
+
+    #[test]
+    fn shared_queries_do_not_conflict() {
+        fn read_twice(first: Query<(&Banana2,), ()>, second: Query<(&Banana, &Banana2), ()>) {
+            assert_eq!(first.len(), 2);
+            assert_eq!(second.len(), 1);
+        }
+        fn write_restricted(
+            _: Query<(&mut Banana2,), (Banana,)>,
+            _: Query<(&Banana, &Banana2), ()>,
+        ) {
+        }
+
+        let mut world = dummy_world();
+        world.add_system(read_twice).unwrap();
+        world.add_system(write_restricted).unwrap();
+        world.run_all_systems();
+    }
+
+    #[test]
+    fn read_write_queries_conflict() {
+        fn read_write(_: Query<(&Banana2,), ()>, _: Query<(&mut Banana2, &Banana), ()>) {}
+        fn write_write(_: Query<(&mut Banana,), ()>, _: Query<(&mut Banana,), ()>) {}
+
+        let mut world = dummy_world();
+        assert!(matches!(
+            world.add_system(read_write),
+            Err(SystemParamError::MustRestrictQuery { .. })
+        ));
+        assert!(matches!(
+            world.add_system(write_write),
+            Err(SystemParamError::MustRestrictQuery { .. })
+        ));
+    }
+
+    #[test]
+    fn query_terms_in_any_order() {
+        let mut world = dummy_world();
+        let mut state = None;
+        let query: Query<(&Banana2, &Banana), ()> = Query::from_world(&mut world, &mut state);
+        assert_eq!(query.len(), 1);
+        assert_banana2_values!(query, 0, [23]);
+    }
+
+    #[test]
+    fn optional_query_terms() {
+        let mut world = dummy_world();
+        let mut state = None;
+        let query: Query<(&Banana, Option<&mut Banana2>), ()> =
+            Query::from_world(&mut world, &mut state);
+        assert_eq!(query.len(), 2);
+        for result in query.iter() {
+            match result.entity.index() {
+                0 => assert!(result.components.1.is_none()),
+                1 => assert_eq!(result.components.1.as_ref().unwrap().0, 23),
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    #[test]
+    fn optional_query_terms_are_checked_for_conflicts() {
+        fn optional_write(_: Query<(&Banana,), ()>, _: Query<(Option<&mut Banana>,), ()>) {}
+        fn optional_reads(_: Query<(&Banana2,), ()>, _: Query<(Option<&Banana2>, &Banana), ()>) {}
+
+        let mut world = dummy_world();
+        assert!(matches!(
+            world.add_system(optional_write),
+            Err(SystemParamError::MustRestrictQuery { .. })
+        ));
+        world.add_system(optional_reads).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "duplicate component type in query")]
+    fn test_query_with_duplicate_optional_component_panics() {
+        let mut world = dummy_world();
+        let mut state = None;
+        let _query: Query<(&Banana, Option<&Banana>), ()> =
+            Query::from_world(&mut world, &mut state);
+    }
+
+    #[derive(Resource, Debug, PartialEq)]
+    struct Score(usize);
+
+    #[test]
+    fn resource_store() {
+        let mut world = World::new();
+        assert!(world.resource::<Score>().is_none());
+        assert!(world.insert_resource(Score(1)).is_none());
+        world.resource_mut::<Score>().unwrap().0 += 1;
+        assert_eq!(world.resource::<Score>(), Some(&Score(2)));
+        assert_eq!(world.insert_resource(Score(5)), Some(Score(2)));
+        assert_eq!(world.remove_resource::<Score>(), Some(Score(5)));
+        assert!(world.resource::<Score>().is_none());
+    }
+
+    #[test]
+    fn resource_system_params() {
+        fn count(mut score: ResMut<Score>, query: Query<(&Banana,), ()>) {
+            score.0 += query.len();
+        }
+        fn read(score: Res<Score>, other: Res<Score>) {
+            assert_eq!(score.0, other.0);
+        }
+
+        let mut world = dummy_world();
+        world.insert_resource(Score(0));
+        world.add_system(count).unwrap();
+        world.add_system(read).unwrap();
+        world.run_all_systems();
+        world.run_all_systems();
+        assert_eq!(world.resource::<Score>(), Some(&Score(4)));
+    }
+
+    #[test]
+    fn conflicting_resource_access_is_rejected() {
+        fn read_and_write(_: Res<Score>, _: ResMut<Score>) {}
+        fn write_twice(_: ResMut<Score>, _: ResMut<Score>) {}
+
+        let mut world = World::new();
+        assert!(matches!(
+            world.add_system(read_and_write),
+            Err(SystemParamError::ConflictingResourceAccess { .. })
+        ));
+        assert!(matches!(
+            world.add_system(write_twice),
+            Err(SystemParamError::ConflictingResourceAccess { .. })
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "does not exist in the world")]
+    fn missing_resource_panics() {
+        fn read(_: Res<Score>) {}
+
+        let mut world = World::new();
+        world.add_system(read).unwrap();
+        world.run_all_systems();
+    }
+
+    #[derive(Resource, Default)]
+    struct RunLog(Vec<&'static str>);
+
+    fn log_a(mut log: ResMut<RunLog>) {
+        log.0.push("a");
+    }
+    fn log_b(mut log: ResMut<RunLog>) {
+        log.0.push("b");
+    }
+    fn log_c(mut log: ResMut<RunLog>) {
+        log.0.push("c");
+    }
+
+    #[test]
+    fn systems_run_in_insertion_order_without_constraints() {
+        let mut world = World::new();
+        world.insert_resource(RunLog::default());
+        world.add_system(log_b).unwrap();
+        world.add_system(log_a).unwrap();
+        world.add_system(log_c).unwrap();
+        world.run_all_systems();
+        assert_eq!(world.resource::<RunLog>().unwrap().0, ["b", "a", "c"]);
+    }
+
+    #[test]
+    fn systems_follow_ordering_constraints() {
+        let mut world = World::new();
+        world.insert_resource(RunLog::default());
+        world.add_system(log_c.label("c").after("b")).unwrap();
+        world.add_system(log_b.label("b").after("a")).unwrap();
+        world
+            .add_system(log_a.label("a").before("c").after("missing"))
+            .unwrap();
+        world.run_all_systems();
+        world.run_all_systems();
+        assert_eq!(
+            world.resource::<RunLog>().unwrap().0,
+            ["a", "b", "c", "a", "b", "c"]
+        );
+    }
+
+    #[test]
+    fn ordering_cycle_is_reported() {
+        let mut world = World::new();
+        world.insert_resource(RunLog::default());
+        world.add_system(log_a.label("a").after("c")).unwrap();
+        world.add_system(log_b.label("b").after("a")).unwrap();
+        world.add_system(log_c.label("c").after("b")).unwrap();
+
+        let Err(ScheduleBuildError::Cycle(cycle)) = world.build_schedule(ScheduleLabel::Update)
+        else {
+            panic!("expected a cycle");
+        };
+        assert_eq!(cycle.len(), 4);
+        assert_eq!(cycle.first(), cycle.last());
+        for name in ["log_a", "log_b", "log_c"] {
+            assert!(cycle.iter().any(|system| system.ends_with(name)));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "form a cycle")]
+    fn running_a_cyclic_schedule_panics() {
+        let mut world = World::new();
+        world.insert_resource(RunLog::default());
+        world.add_system(log_a.label("a").after("a")).unwrap();
+        world.run_all_systems();
+    }
+
+    #[test]
+    fn update_runs_schedules_in_order() {
+        let mut world = World::new();
+        world.insert_resource(RunLog::default());
+        world.set_fixed_timestep(Duration::from_millis(10));
+        world
+            .add_system_to(ScheduleLabel::PostUpdate, |mut log: ResMut<RunLog>| {
+                log.0.push("post")
+            })
+            .unwrap();
+        world
+            .add_system(|mut log: ResMut<RunLog>| log.0.push("update"))
+            .unwrap();
+        world
+            .add_system_to(ScheduleLabel::FixedUpdate, |mut log: ResMut<RunLog>| {
+                log.0.push("fixed")
+            })
+            .unwrap();
+        world
+            .add_system_to(ScheduleLabel::PreUpdate, |mut log: ResMut<RunLog>| {
+                log.0.push("pre")
+            })
+            .unwrap();
+        world
+            .add_system_to(ScheduleLabel::Startup, |mut log: ResMut<RunLog>| {
+                log.0.push("startup")
+            })
+            .unwrap();
+
+        world.update(Duration::from_millis(25));
+        assert_eq!(
+            world.resource::<RunLog>().unwrap().0,
+            ["startup", "pre", "fixed", "fixed", "update", "post"]
+        );
+
+        world.resource_mut::<RunLog>().unwrap().0.clear();
+        // The 5ms left over from the last update add up to a fixed step
+        world.update(Duration::from_millis(5));
+        world.update(Duration::from_millis(4));
+        assert_eq!(
+            world.resource::<RunLog>().unwrap().0,
+            ["pre", "fixed", "update", "post", "pre", "update", "post"]
+        );
+    }
+
+    #[test]
+    fn custom_schedules_only_run_on_demand() {
+        let mut world = World::new();
+        world.insert_resource(RunLog::default());
+        let custom = ScheduleLabel::Custom("custom");
+        world.add_system_to(custom, log_a).unwrap();
+        world
+            .add_system_to(ScheduleLabel::Custom("other"), log_b)
+            .unwrap();
+
+        world.update(Duration::ZERO);
+        assert!(world.resource::<RunLog>().unwrap().0.is_empty());
+
+        world.run_schedule(custom);
+        world.run_schedule(ScheduleLabel::Custom("missing"));
+        assert_eq!(world.resource::<RunLog>().unwrap().0, ["a"]);
+    }
+
+    #[test]
+    fn inserted_custom_schedules_run_every_update() {
+        let mut world = World::new();
+        world.insert_resource(RunLog::default());
+        world.add_system(log_a).unwrap();
+        world
+            .add_system_to(ScheduleLabel::Custom("before"), log_b)
+            .unwrap();
+        world
+            .add_system_to(ScheduleLabel::Custom("after"), log_c)
+            .unwrap();
+        world.insert_schedule_after(ScheduleLabel::Custom("after"), ScheduleLabel::Update);
+        world.insert_schedule_after(ScheduleLabel::Custom("before"), ScheduleLabel::PreUpdate);
+
+        world.update(Duration::ZERO);
+        world.update(Duration::ZERO);
+        assert_eq!(
+            world.resource::<RunLog>().unwrap().0,
+            ["b", "a", "c", "b", "a", "c"]
+        );
+    }
+
+    #[test]
+    #[should_panic = "is not run by World::update"]
+    fn schedules_cannot_be_inserted_after_startup() {
+        World::new().insert_schedule_after(ScheduleLabel::Custom("custom"), ScheduleLabel::Startup);
+    }
+
+    #[test]
+    fn fixed_update_catch_up_is_capped() {
+        let mut world = World::new();
+        world.insert_resource(RunLog::default());
+        world.set_fixed_timestep(Duration::from_millis(10));
+        world.set_max_fixed_steps(3);
+        world
+            .add_system_to(ScheduleLabel::FixedUpdate, log_a)
+            .unwrap();
+
+        world.update(Duration::from_millis(105));
+        assert_eq!(world.resource::<RunLog>().unwrap().0, ["a"; 3]);
+        // The skipped steps are not caught up with later, but the 5ms left over are kept
+        world.update(Duration::from_millis(5));
+        assert_eq!(world.resource::<RunLog>().unwrap().0, ["a"; 4]);
+    }
+
+    #[test]
+    fn run_conditions_skip_systems() {
+        let mut world = World::new();
+        world.insert_resource(RunLog::default());
+        world.insert_resource(Score(0));
+        world
+            .add_system(log_a.run_if(|score: Res<Score>| score.0 > 0))
+            .unwrap();
+        world
+            .add_system(
+                log_b
+                    .run_if(|score: Res<Score>| score.0 > 0)
+                    .run_if(|query: Query<(&Banana,), ()>| !query.is_empty()),
+            )
+            .unwrap();
+        world.run_all_systems();
+        assert!(world.resource::<RunLog>().unwrap().0.is_empty());
+
+        world.resource_mut::<Score>().unwrap().0 = 1;
+        world.run_all_systems();
+        assert_eq!(world.resource::<RunLog>().unwrap().0, ["a"]);
+
+        world.spawn((Banana,));
+        world.run_all_systems();
+        assert_eq!(world.resource::<RunLog>().unwrap().0, ["a", "a", "b"]);
+    }
+
+    #[test]
+    fn skipped_systems_do_not_fetch_params() {
+        let mut world = World::new();
+        // The system would panic on the missing resource if its params were fetched
+        world
+            .add_system((|_: Res<Score>| {}).run_if(|| false))
+            .unwrap();
+        world.run_all_systems();
+    }
+
+    #[test]
+    fn parallel_executor_initializes_systems_once_their_conditions_pass() {
+        let mut world = World::new();
+        world.insert_resource(RunLog::default());
+        world.insert_resource(Score(0));
+        world.set_executor(ScheduleLabel::Update, ExecutorKind::Parallel);
+        // The system would panic on the missing resource if its params were fetched
+        world
+            .add_system((|_: Res<ThreadLog>| {}).run_if(|| false))
+            .unwrap();
+        world
+            .add_system(log_a.run_if(|score: Res<Score>| score.0 > 0))
+            .unwrap();
+        world
+            .add_system(|mut score: ResMut<Score>| score.0 += 1)
+            .unwrap();
+        world.run_all_systems();
+        assert!(world.resource::<RunLog>().unwrap().0.is_empty());
+
+        world.run_all_systems();
+        assert_eq!(world.resource::<RunLog>().unwrap().0, ["a"]);
+    }
+
+    /// Makes two systems wait for each other, which only succeeds if they run at the same time
+    #[derive(Default)]
+    struct Meeting {
+        arrived: Mutex<usize>,
+        all_arrived: Condvar,
+    }
+
+    impl Meeting {
+        /// Waits for the other system to arrive too. The timeout only keeps the test from
+        /// hanging if the systems run one after the other.
+        fn meet(&self) -> bool {
+            let mut arrived = self.arrived.lock().unwrap();
+            *arrived += 1;
+            self.all_arrived.notify_all();
+            let (_arrived, timeout) = self
+                .all_arrived
+                .wait_timeout_while(arrived, Duration::from_secs(10), |arrived| *arrived < 2)
+                .unwrap();
+            !timeout.timed_out()
+        }
+    }
+
+    #[test]
+    fn non_conflicting_systems_run_in_parallel() {
+        if system::available_threads() < 2 {
+            return;
+        }
+        let mut world = dummy_world();
+        let meeting = Arc::new(Meeting::default());
+        let met = Arc::new([AtomicBool::new(false), AtomicBool::new(false)]);
+
+        let (system_meeting, system_met) = (meeting.clone(), met.clone());
+        world
+            .add_system(move |_: Query<(&mut Banana,), ()>| {
+                system_met[0].store(system_meeting.meet(), atomic::Ordering::SeqCst);
+            })
+            .unwrap();
+        let (system_meeting, system_met) = (meeting.clone(), met.clone());
+        world
+            .add_system(move |_: Query<(&mut Banana2,), ()>| {
+                system_met[1].store(system_meeting.meet(), atomic::Ordering::SeqCst);
+            })
+            .unwrap();
+        world.run_all_systems();
+
+        assert!(met.iter().all(|met| met.load(atomic::Ordering::SeqCst)));
+    }
+
+    #[derive(Resource, Default)]
+    struct ThreadLog(Vec<std::thread::ThreadId>);
+
+    #[test]
+    fn sequential_executor_runs_on_calling_thread() {
+        let mut world = World::new();
+        world.insert_resource(ThreadLog::default());
+        world.set_executor(ScheduleLabel::Update, ExecutorKind::Sequential);
+        world
+            .add_system(|mut log: ResMut<ThreadLog>| log.0.push(std::thread::current().id()))
+            .unwrap();
+        world.run_all_systems();
+        assert_eq!(
+            world.resource::<ThreadLog>().unwrap().0,
+            [std::thread::current().id()]
+        );
+    }
+
+    #[test]
+    fn executor_set_by_commands_is_kept() {
+        let mut world = World::new();
+        world
+            .add_system(|commands: &mut Commands| {
+                commands.add(|world: &mut World| {
+                    world.set_executor(ScheduleLabel::Update, ExecutorKind::Sequential);
+                });
+            })
+            .unwrap();
+        world.run_all_systems();
+        assert_eq!(
+            world.schedules[&ScheduleLabel::Update].executor,
+            Some(ExecutorKind::Sequential)
+        );
+    }
+
+    #[test]
+    #[should_panic = "was run again while it was already running"]
+    fn schedules_cannot_run_themselves() {
+        let mut world = World::new();
+        world
+            .add_system(|commands: &mut Commands| {
+                commands.add(|world: &mut World| world.run_all_systems());
+            })
+            .unwrap();
+        world.run_all_systems();
+    }
+
+    #[test]
+    fn system_access_is_retained() {
+        let mut world = World::new();
+        let mut args = SystemWorldArgs::new(
+            &mut world.components_manager,
+            &mut world.entity_manager,
+            &mut world.resources,
+        );
+        let reader = (|_: Query<(&Banana,), ()>, _: Res<Score>| {})
+            .parse(&mut args)
+            .unwrap();
+        let other_reader = (|_: Query<(&Banana, &Banana2), ()>, _: Res<Score>| {})
+            .parse(&mut args)
+            .unwrap();
+        let writer = (|_: Query<(&mut Banana,), ()>| {})
+            .parse(&mut args)
+            .unwrap();
+        let restricted_writer = (|_: Query<(&mut Banana,), (Banana2,)>| {})
+            .parse(&mut args)
+            .unwrap();
+        let unchecked = unsafe { (|_: Res<Score>| {}).parse_unchecked() };
+
+        let access = |system: &dyn system::System| system.access().unwrap().clone();
+        assert!(!access(&*reader).conflicts_with(&access(&*other_reader)));
+        assert!(access(&*reader).conflicts_with(&access(&*writer)));
+        assert!(!access(&*other_reader).conflicts_with(&access(&*restricted_writer)));
+        assert!(unchecked.access().is_none());
+    }
+
+    #[test]
+    fn par_for_each_visits_every_entity() {
+        let mut world = dummy_world();
+        for value in 0..100 {
+            world.spawn((Banana2(value), Banana3));
+        }
+        let mut state = None;
+        let mut query: Query<(&mut Banana2,), ()> = Query::from_world(&mut world, &mut state);
+
+        query.par_for_each_mut(7, |mut result| result.components.0.0 += 1);
+        let sum = AtomicUsize::new(0);
+        let visited = AtomicUsize::new(0);
+        query.par_for_each(7, |result| {
+            sum.fetch_add(result.components.0.0, atomic::Ordering::Relaxed);
+            visited.fetch_add(1, atomic::Ordering::Relaxed);
+        });
+
+        assert_eq!(visited.into_inner(), 102);
+        assert_eq!(sum.into_inner(), (1..=100).sum::<usize>() + 24 + 25);
+    }
+
+    #[test]
+    #[should_panic(expected = "batch size")]
+    fn par_for_each_rejects_empty_batches() {
+        let mut world = dummy_world();
+        let mut state = None;
+        let query: Query<(&Banana,), ()> = Query::from_world(&mut world, &mut state);
+        query.par_for_each(0, |_| {});
+    }
+
+    #[derive(Event, Debug, PartialEq, Clone)]
+    struct Ping(usize);
+
+    #[derive(Resource, Default)]
+    struct Received(Vec<usize>);
+
+    fn receive_pings(mut reader: EventReader<Ping>, mut received: ResMut<Received>) {
+        received.0.extend(reader.read().map(|ping| ping.0));
+    }
+
+    #[test]
+    fn event_readers_see_each_event_once() {
+        let mut world = World::new();
+        world.add_event::<Ping>();
+        world.insert_resource(Received::default());
+        world.add_system(receive_pings.after("writer")).unwrap();
+        world
+            .add_system(
+                (|mut writer: EventWriter<Ping>| writer.send_batch([Ping(1), Ping(2)]))
+                    .label("writer"),
+            )
+            .unwrap();
+        world.run_all_systems();
+        world.send_event(Ping(3));
+        world.run_all_systems();
+
+        assert_eq!(world.resource::<Received>().unwrap().0, [1, 2, 3, 1, 2]);
+    }
+
+    #[test]
+    fn event_readers_have_separate_cursors() {
+        let mut world = World::new();
+        world.add_event::<Ping>();
+        world.insert_resource(Received::default());
+        world.add_system(receive_pings).unwrap();
+        world.send_event(Ping(1));
+        world.run_all_systems();
+
+        // A reader added later still sees the events that were not cleaned up yet
+        world
+            .add_system(
+                |mut reader: EventReader<Ping>, mut received: ResMut<Received>| {
+                    assert_eq!(reader.len(), 1);
+                    received.0.extend(reader.read().map(|ping| ping.0 * 10));
+                    assert!(reader.is_empty());
+                },
+            )
+            .unwrap();
+        world.run_all_systems();
+
+        assert_eq!(world.resource::<Received>().unwrap().0, [1, 10]);
+    }
+
+    #[test]
+    fn events_are_dropped_after_two_updates() {
+        let mut world = World::new();
+        world.add_event::<Ping>();
+        world.insert_resource(Received::default());
+        world
+            .add_system_to(ScheduleLabel::Custom("read"), receive_pings)
+            .unwrap();
+
+        world.send_event(Ping(1));
+        world.update(Duration::ZERO);
+        world.send_event(Ping(2));
+        world.update(Duration::ZERO);
+        world.run_schedule(ScheduleLabel::Custom("read"));
+
+        assert_eq!(world.resource::<Received>().unwrap().0, [2]);
+    }
+
+    #[test]
+    #[should_panic(expected = "does not exist")]
+    fn event_must_be_added() {
+        let mut world = World::new();
+        world.add_system(receive_pings).unwrap();
+        world.insert_resource(Received::default());
+        world.run_all_systems();
+    }
+
+    #[derive(Resource)]
+    struct Bump(usize);
+
+    #[test]
+    fn changed_filter_only_sees_written_components() {
+        let mut world = dummy_world();
+        world.insert_resource(Received::default());
+        world.insert_resource(Bump(0));
+        world
+            .add_system(
+                (|mut query: Query<(&mut Banana2,), ()>, bump: Res<Bump>| {
+                    for mut result in &mut query {
+                        if result.components.0.0 == bump.0 {
+                            result.components.0.0 += 100;
+                        }
+                    }
+                })
+                .label("writer"),
+            )
+            .unwrap();
+        world
+            .add_system(
+                (|query: Query<(&Banana2,), (Changed<Banana2>,)>,
+                  mut received: ResMut<Received>| {
+                    received
+                        .0
+                        .extend(query.iter().map(|result| result.components.0.0));
+                })
+                .after("writer"),
+            )
+            .unwrap();
+
+        // Everything is new to a system that never ran
+        world.run_all_systems();
+        world.insert_resource(Bump(23));
+        world.run_all_systems();
+        world.insert_resource(Bump(0));
+        world.run_all_systems();
+
+        assert_eq!(world.resource::<Received>().unwrap().0, [23, 24, 123]);
+    }
+
+    #[test]
+    fn added_filter_ignores_replaced_components() {
+        let mut world = dummy_world();
+        world.insert_resource(Received::default());
+        world
+            .add_system(
+                |query: Query<(&Banana2,), (Added<Banana2>,)>, mut received: ResMut<Received>| {
+                    received
+                        .0
+                        .extend(query.iter().map(|result| result.components.0.0));
+                    assert_eq!(query.len(), query.iter().count());
+                },
+            )
+            .unwrap();
+        world.run_all_systems();
+
+        let replaced = EntityId::new(2, 0);
+        world.insert(replaced, (Banana2(50),));
+        world.spawn((Banana2(60),));
+        world.run_all_systems();
+
+        let mut state = None;
+        let query: Query<(&Banana2,), (Changed<Banana2>, Banana)> =
+            Query::from_world(&mut world, &mut state);
+        assert!(query.contains(replaced));
+        assert!(!query.contains(EntityId::new(1, 0)));
+
+        assert_eq!(world.resource::<Received>().unwrap().0, [23, 24, 60]);
+    }
+
+    #[test]
+    fn mut_tracks_writes() {
+        let mut world = dummy_world();
+        let mut state = None;
+        let mut query: Query<(&mut Banana2,), ()> = Query::from_world(&mut world, &mut state);
+        let (mut banana,) = query.get_mut(EntityId::new(1, 0)).unwrap();
+        // The query helper sees everything as new, like a system that never ran
+        assert!(banana.is_added() && banana.is_changed());
+        banana.bypass_change_detection().0 = 0;
+        assert_eq!(banana.0, 0);
+    }
+
+    #[test]
+    fn removed_components_are_reported_once() {
+        let mut world = dummy_world();
+        world.insert_resource(Received::default());
+        world
+            .add_system(
+                |mut removed: RemovedComponents<Banana2>, mut received: ResMut<Received>| {
+                    received
+                        .0
+                        .extend(removed.read().map(|entity| entity.index()));
+                    assert!(removed.is_empty());
+                },
+            )
+            .unwrap();
+        world
+            .add_system(|mut removed: RemovedComponents<Banana3>| {
+                assert_eq!(removed.len(), 0);
+                assert_eq!(removed.read().count(), 0);
+            })
+            .unwrap();
+
+        world.remove::<(Banana2,)>(EntityId::new(1, 0));
+        world.remove::<(Banana2,)>(EntityId::new(0, 0));
+        world.despawn(&EntityId::new(2, 0));
+        world.run_all_systems();
+        world.run_all_systems();
+
+        assert_eq!(world.resource::<Received>().unwrap().0, [1, 2]);
+    }
+
+    #[test]
+    fn removed_components_are_dropped_after_two_updates() {
+        let mut world = dummy_world();
+        world.insert_resource(Received::default());
+        world
+            .add_system_to(
+                ScheduleLabel::Custom("read"),
+                |mut removed: RemovedComponents<Banana>, mut received: ResMut<Received>| {
+                    received
+                        .0
+                        .extend(removed.read().map(|entity| entity.index()));
+                },
+            )
+            .unwrap();
+
+        world.despawn(&EntityId::new(0, 0));
+        world.update(Duration::ZERO);
+        world.remove::<(Banana,)>(EntityId::new(1, 0));
+        world.update(Duration::ZERO);
+        world.run_schedule(ScheduleLabel::Custom("read"));
+
+        assert_eq!(world.resource::<Received>().unwrap().0, [1]);
+    }
+
+    #[test]
+    fn removals_are_only_recorded_when_read() {
+        let mut world = dummy_world();
+        world.despawn(&EntityId::new(0, 0));
+        world.run_all_systems();
+        let banana = world
+            .components_manager
+            .get_component_id::<Banana>()
+            .unwrap();
+        assert!(world.entity_manager.removed(banana).is_none());
+    }
+
+    #[derive(Resource, Default)]
+    struct HookLog(Vec<&'static str>);
+
+    fn log_hook(world: &mut World, name: &'static str) {
+        world.resource_mut::<HookLog>().unwrap().0.push(name);
+    }
+
+    #[test]
+    fn hooks_run_on_add_insert_and_remove() {
+        let mut world = World::new();
+        world.insert_resource(HookLog::default());
+        world
+            .register_component_hooks::<Banana3>()
+            .on_add(|world, _| log_hook(world, "add"))
+            .on_insert(|world, _| log_hook(world, "insert"))
+            .on_remove(|world, entity| {
+                // The component can still be read while its hook runs
+                let mut state = None;
+                let query: Query<(&Banana3,), ()> = Query::from_world(world, &mut state);
+                assert!(query.contains(entity));
+                log_hook(world, "remove");
+            });
+
+        let id = world.spawn((Banana3,));
+        world.insert(id, (Banana3, Banana));
+        world.remove::<(Banana3, Banana2)>(id);
+        world.remove::<(Banana3,)>(id);
+        world.insert(id, (Banana3,));
+        world.despawn(&id);
+
+        assert_eq!(
+            world.resource::<HookLog>().unwrap().0,
+            [
+                "add", "insert", "insert", "remove", "add", "insert", "remove"
+            ]
+        );
+    }
+
+    fn tag_with_banana(world: &mut World, entity: EntityId) {
+        world.insert(entity, (Banana,));
+    }
+
+    #[derive(Component)]
+    #[component(on_add = tag_with_banana)]
+    struct Tagged;
+
+    #[test]
+    fn derived_hooks_run_for_commands() {
+        let mut world = World::new();
+        world
+            .add_system(|commands: &mut Commands| {
+                commands.spawn((Tagged,));
+            })
+            .unwrap();
+        world.run_all_systems();
+
+        let mut state = None;
+        let query: Query<(&Tagged, &Banana), ()> = Query::from_world(&mut world, &mut state);
+        assert_eq!(query.len(), 1);
+    }
+
+    #[derive(Event)]
+    struct Damage(usize);
+
+    #[test]
+    fn observers_run_for_their_targets() {
+        let mut world = dummy_world();
+        world.insert_resource(Received::default());
+        world
+            .observe(
+                |trigger: Trigger<Damage>, mut query: Query<(&mut Banana2,), ()>| {
+                    if let Some(target) = trigger.target()
+                        && let Ok((mut banana,)) = query.get_mut(target)
+                    {
+                        banana.0 -= trigger.event().0;
+                    }
+                },
+            )
+            .unwrap();
+        world
+            .observe_entity(
+                EntityId::new(2, 0),
+                |trigger: Trigger<Damage>, mut received: ResMut<Received>| {
+                    received.0.push(trigger.event().0);
+                },
+            )
+            .unwrap();
+
+        world.trigger(Damage(1));
+        world.trigger_targets(Damage(3), [EntityId::new(1, 0), EntityId::new(2, 0)]);
+
+        let mut state = None;
+        let query: Query<(&Banana2,), ()> = Query::from_world(&mut world, &mut state);
+        let healths: Vec<_> = query.iter().map(|result| result.components.0.0).collect();
+        assert_eq!(healths, [20, 21]);
+        assert_eq!(world.resource::<Received>().unwrap().0, [3]);
+    }
+
+    #[test]
+    fn observers_queue_commands() {
+        let mut world = World::new();
+        world.insert_resource(Received::default());
+        world
+            .observe(|trigger: Trigger<Damage>, commands: &mut Commands| {
+                commands.spawn((Banana,));
+                if trigger.event().0 > 1 {
+                    commands.trigger(Damage(trigger.event().0 - 1));
+                }
+            })
+            .unwrap();
+        world
+            .observe(|trigger: Trigger<Damage>, mut received: ResMut<Received>| {
+                received.0.push(trigger.event().0);
+            })
+            .unwrap();
+        world
+            .add_system(|commands: &mut Commands| {
+                commands.trigger(Damage(3));
+            })
+            .unwrap();
+        world.run_all_systems();
+
+        let mut state = None;
+        let query: Query<(&Banana,), ()> = Query::from_world(&mut world, &mut state);
+        assert_eq!(query.len(), 3);
+        assert_eq!(world.resource::<Received>().unwrap().0, [3, 2, 1]);
+    }
+
+    #[test]
+    fn commands_apply_in_order() {
+        let mut world = World::new();
+        world
+            .add_system(|commands: &mut Commands| {
+                let spawned = commands.spawn((Banana,)).id();
+                commands.insert(spawned, (Banana2(0),));
+                commands.despawn(spawned);
+                commands.spawn((Banana2(1),));
+            })
+            .unwrap();
+        world.run_all_systems();
+
+        let mut state = None;
+        let query: Query<(&Banana2,), ()> = Query::from_world(&mut world, &mut state);
+        let values: Vec<_> = query.iter().map(|result| result.components.0.0).collect();
+        assert_eq!(values, [1]);
+    }
+
+    #[test]
+    fn command_buffers_flush_in_schedule_order() {
+        let mut world = World::new();
+        world.insert_resource(Received::default());
+        world
+            .observe(|trigger: Trigger<Damage>, mut received: ResMut<Received>| {
+                received.0.push(trigger.event().0);
+            })
+            .unwrap();
+        world
+            .add_system(
+                (|commands: &mut Commands| {
+                    commands.trigger(Damage(3));
+                    commands.trigger(Damage(4));
+                })
+                .after("first"),
+            )
+            .unwrap();
+        world
+            .add_system(|commands: &mut Commands| commands.trigger(Damage(5)))
+            .unwrap();
+        world
+            .add_system(
+                (|commands: &mut Commands| {
+                    commands.trigger(Damage(1));
+                    commands.trigger(Damage(2));
+                })
+                .label("first"),
+            )
+            .unwrap();
+        world.run_all_systems();
+
+        assert_eq!(world.resource::<Received>().unwrap().0, [5, 1, 2, 3, 4]);
+    }
+
+    fn count_bananas(query: Query<(&Banana,), ()>, mut received: ResMut<Received>) {
+        received.0.push(query.len());
+    }
+
+    #[test]
+    fn sync_point_is_inserted_after_spawner() {
+        let mut world = World::new();
+        world.insert_resource(Received::default());
+        world.add_system(count_bananas.after("spawn")).unwrap();
+        world
+            .add_system(
+                (|commands: &mut Commands| {
+                    commands.spawn((Banana,));
+                })
+                .label("spawn"),
+            )
+            .unwrap();
+        world.run_all_systems();
+        world.run_all_systems();
+
+        assert_eq!(world.resource::<Received>().unwrap().0, [1, 2]);
+    }
+
+    #[test]
+    fn apply_deferred_applies_commands_mid_schedule() {
+        let mut world = World::new();
+        world.insert_resource(Received::default());
+        world.add_system(count_bananas.label("count")).unwrap();
+        world
+            .add_system(ApplyDeferred.after("spawn").before("count"))
+            .unwrap();
+        world
+            .add_system(
+                (|commands: &mut Commands| {
+                    commands.spawn((Banana,));
+                })
+                .label("spawn"),
+            )
+            .unwrap();
+        world.run_all_systems();
+
+        assert_eq!(world.resource::<Received>().unwrap().0, [1]);
+    }
+
+    #[test]
+    fn entity_commands_chain() {
+        let mut world = dummy_world();
+        world
+            .add_system(|commands: &mut Commands| {
+                commands
+                    .spawn((Banana,))
+                    .insert((Banana2(7),))
+                    .remove::<(Banana,)>()
+                    .add(|entity, world| {
+                        world.get_mut::<Banana2>(entity).unwrap().0 += 1;
+                    });
+                commands.entity(EntityId::new(0, 0)).despawn();
+            })
+            .unwrap();
+        world.run_all_systems();
+
+        assert!(!world.contains(EntityId::new(0, 0)));
+        let spawned = EntityId::new(3, 0);
+        assert_eq!(world.get::<Banana2>(spawned).unwrap().0, 8);
+        assert!(world.get::<Banana>(spawned).is_none());
+    }
+
+    #[test]
+    fn children_are_added_and_moved() {
+        let mut world = dummy_world();
+        world
+            .add_system(|commands: &mut Commands| {
+                let first = EntityId::new(0, 0);
+                commands.entity(first).with_children(|parent| {
+                    parent.spawn((Banana3,));
+                    parent.spawn((Banana3,)).insert((Banana2(5),));
+                });
+                commands
+                    .entity(EntityId::new(1, 0))
+                    .add_child(EntityId::new(2, 0))
+                    .add_child(EntityId::new(4, 0));
+            })
+            .unwrap();
+        world.run_all_systems();
+
+        let children: Vec<_> = world
+            .get::<Children>(EntityId::new(0, 0))
+            .unwrap()
+            .iter()
+            .collect();
+        assert_eq!(children, [EntityId::new(3, 0)]);
+        let children: Vec<_> = world
+            .get::<Children>(EntityId::new(1, 0))
+            .unwrap()
+            .iter()
+            .collect();
+        assert_eq!(children, [EntityId::new(2, 0), EntityId::new(4, 0)]);
+        let moved = world.get::<Parent>(EntityId::new(4, 0)).unwrap();
+        assert_eq!(moved.get(), EntityId::new(1, 0));
+        assert_eq!(world.get::<Banana2>(EntityId::new(4, 0)).unwrap().0, 5);
+    }
+
+    /// Makes entity 1 and 2 children of entity 0
+    fn family_world() -> World {
+        let mut world = dummy_world();
+        world
+            .add_system(|commands: &mut Commands| {
+                commands
+                    .entity(EntityId::new(0, 0))
+                    .add_child(EntityId::new(1, 0))
+                    .add_child(EntityId::new(2, 0));
+            })
+            .unwrap();
+        world.run_all_systems();
+        world
+    }
+
+    #[test]
+    fn despawned_child_leaves_its_parent() {
+        let mut world = family_world();
+        world.despawn(&EntityId::new(1, 0));
+
+        let children: Vec<_> = world
+            .get::<Children>(EntityId::new(0, 0))
+            .unwrap()
+            .iter()
+            .collect();
+        assert_eq!(children, [EntityId::new(2, 0)]);
+    }
+
+    #[test]
+    fn despawned_parent_orphans_its_children() {
+        let mut world = family_world();
+        world.despawn(&EntityId::new(0, 0));
+
+        assert!(world.get::<Parent>(EntityId::new(1, 0)).is_none());
+        assert!(world.get::<Parent>(EntityId::new(2, 0)).is_none());
+        assert!(world.contains(EntityId::new(2, 0)));
+    }
+
+    #[test]
+    fn hierarchy_cycles_are_rejected() {
+        let mut world = family_world();
+        world.set_command_error_policy(CommandErrorPolicy::Collect);
+        world
+            .add_system_to(ScheduleLabel::Custom("cycle"), |commands: &mut Commands| {
+                let (parent, child) = (EntityId::new(0, 0), EntityId::new(1, 0));
+                commands.entity(parent).add_child(parent);
+                commands.entity(child).add_child(parent);
+            })
+            .unwrap();
+        world.run_schedule(ScheduleLabel::Custom("cycle"));
+
+        let (parent, child) = (EntityId::new(0, 0), EntityId::new(1, 0));
+        assert_eq!(
+            world.take_command_errors(),
+            [
+                CommandError::HierarchyCycle {
+                    parent,
+                    child: parent
+                },
+                CommandError::HierarchyCycle {
+                    parent: child,
+                    child: parent
+                },
+            ]
+        );
+        assert!(world.get::<Parent>(parent).is_none());
+    }
+
+    struct Record(usize);
+
+    impl Command for Record {
+        fn apply(self, world: &mut World) -> Result<(), CommandError> {
+            world.resource_mut::<Received>().unwrap().0.push(self.0);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn custom_commands_access_the_world() {
+        let mut world = World::new();
+        world.insert_resource(Received::default());
+        world
+            .add_system(|commands: &mut Commands| {
+                commands.add(Record(1));
+                commands.add(|world: &mut World| {
+                    world
+                        .add_system(|commands: &mut Commands| commands.add(Record(2)))
+                        .unwrap();
+                });
+            })
+            .unwrap();
+        world.run_all_systems();
+        world.run_all_systems();
+
+        assert_eq!(world.resource::<Received>().unwrap().0, [1, 1, 2]);
+    }
+
+    #[test]
+    fn failed_commands_are_collected() {
+        let mut world = dummy_world();
+        world.set_command_error_policy(CommandErrorPolicy::Collect);
+        let despawned = EntityId::new(0, 0);
+        world
+            .add_system(move |commands: &mut Commands| {
+                commands.despawn(despawned);
+                commands.entity(despawned).try_despawn();
+                commands.entity(despawned).insert((Banana2(1),));
+                commands.add(|_: &mut World| Err(CommandError::Custom("custom".into())));
+            })
+            .unwrap();
+        world.run_all_systems();
+
+        assert!(!world.contains(despawned));
+        assert_eq!(
+            world.command_errors(),
+            [
+                CommandError::EntityNotFound(despawned),
+                CommandError::Custom("custom".into())
+            ]
+        );
+        world.run_all_systems();
+        assert_eq!(world.take_command_errors().len(), 5);
+        assert!(world.command_errors().is_empty());
+    }
+
+    #[test]
+    fn ignored_and_logged_command_errors_do_not_panic() {
+        for policy in [CommandErrorPolicy::Ignore, CommandErrorPolicy::Log] {
+            let mut world = World::new();
+            world.set_command_error_policy(policy);
+            world.commands.despawn(EntityId::new(0, 0));
+            world.apply_commands();
+            assert!(world.command_errors().is_empty());
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Failed to apply a command")]
+    fn failed_commands_panic_by_default() {
+        let mut world = World::new();
+        world.commands.entity(EntityId::new(0, 0)).despawn();
+        world.apply_commands();
+    }
+
     #[test]
     fn systems_test() {
         fn print_me(
             commands: &mut Commands,
-            _query: Query<(Banana2,), ()>,
-            query2: Query<(Banana,), ()>,
+            _query: Query<(&mut Banana2,), ()>,
+            query2: Query<(&Banana,), ()>,
         ) {
             commands.spawn((Banana,));
-            for result in &query2.results {
+            for result in &query2 {
                 dbg!(&result.components);
             }
         }
@@ -0,0 +1,160 @@
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+};
+
+use crate::{
+    Commands, SystemWorldArgs, World,
+    entity::EntityId,
+    event::Event,
+    system::{SafetyCheck, SystemParam, SystemParamError, SystemRunArgs, SystemWrapper, WorldPtrs},
+};
+
+/// The event an observer reacts to, and the entity it was triggered on if it has a target
+pub struct Trigger<'a, E> {
+    event: &'a E,
+    target: Option<EntityId>,
+}
+
+impl<'a, E> Trigger<'a, E> {
+    pub(crate) fn new(event: &'a E, target: Option<EntityId>) -> Self {
+        Self { event, target }
+    }
+
+    pub fn event(&self) -> &E {
+        self.event
+    }
+
+    /// The entity the event was triggered on, or `None` if it was triggered without targets
+    pub fn target(&self) -> Option<EntityId> {
+        self.target
+    }
+}
+
+/// A system taking a `Trigger<E>` as its first parameter, run right away whenever `E` is
+/// triggered
+pub trait IntoObserver<E: Event, T>: Sized {
+    fn parse(self, args: &mut SystemWorldArgs) -> Result<Box<dyn Observer<E>>, SystemParamError>;
+}
+
+macro_rules! impl_into_observer {
+    ($(($n:tt, $A:ident)),*) => {
+        impl<E, F, $($A: SystemParam,)*> IntoObserver<E, ($($A,)*)> for F
+        where
+            E: Event,
+            F: for<'t> Fn(Trigger<'t, E>, $($A,)*) + Send + 'static
+        {
+            #[allow(unused_variables, unused_mut, clippy::unused_unit)]
+            fn parse(self, args: &mut SystemWorldArgs) -> Result<Box<dyn Observer<E>>, SystemParamError> {
+                let mut safety_check = SafetyCheck::new();
+                $(
+                    if let Some(info) = $A::safety_info(args) {
+                        safety_check.check::<$A>(info)?;
+                    }
+                )*
+                // SAFETY: The parameters were checked above, and observers never run alongside
+                // other systems
+                Ok(Box::new(SystemWrapper::new(
+                    std::any::type_name::<F>(),
+                    |args: &mut SystemWorldArgs| ($($A::init_state(args),)*),
+                    move |state: &mut ($($A::State,)*), args: SystemRunArgs, trigger: Trigger<E>| {
+                        self(trigger, $(unsafe {$A::init(&mut state.$n, args)},)*)
+                    },
+                )))
+            }
+        }
+    };
+}
+
+variadics_please::all_tuples_enumerated!(impl_into_observer, 0, 15, A);
+
+pub trait Observer<E>: Send + 'static {
+    /// Creates the observer's state if it has not been created yet
+    fn initialize(&mut self, args: &mut SystemWorldArgs);
+    /// SAFETY: The observer must have been initialized, and nothing else may access the world
+    /// while it runs
+    unsafe fn run(&mut self, trigger: Trigger<E>, world: WorldPtrs);
+    /// Moves the commands queued by the observer to the end of `commands`
+    fn flush_commands(&mut self, commands: &mut Commands);
+}
+
+impl<E, S, F> Observer<E> for SystemWrapper<S, F>
+where
+    E: Event,
+    S: Send + 'static,
+    F: for<'t> FnMut(&mut S, SystemRunArgs, Trigger<'t, E>) + Send + 'static,
+{
+    fn initialize(&mut self, args: &mut SystemWorldArgs) {
+        SystemWrapper::initialize(self, args);
+    }
+
+    unsafe fn run(&mut self, trigger: Trigger<E>, world: WorldPtrs) {
+        unsafe { self.call_with(world, |fptr, state, args| fptr(state, args, trigger)) };
+    }
+
+    fn flush_commands(&mut self, commands: &mut Commands) {
+        SystemWrapper::flush_commands(self, commands);
+    }
+}
+
+pub(crate) struct ObserverEntry<E> {
+    observer: Box<dyn Observer<E>>,
+    /// The only entity the observer reacts to, or `None` if it reacts to every trigger
+    entity: Option<EntityId>,
+}
+
+impl<E: Event> ObserverEntry<E> {
+    /// Runs the observer if it watches the target of the trigger, then moves the commands it
+    /// queued to the end of the world's commands
+    pub(crate) fn run(&mut self, trigger: Trigger<E>, world: &mut World) {
+        if self.entity.is_none() || self.entity == trigger.target {
+            let mut args = SystemWorldArgs::new(
+                &mut world.components_manager,
+                &mut world.entity_manager,
+                &mut world.resources,
+            );
+            self.observer.initialize(&mut args);
+            // SAFETY: The observer was just initialized, and it has the world to itself
+            unsafe { self.observer.run(trigger, args.ptrs()) };
+            self.observer.flush_commands(&mut world.commands);
+        }
+    }
+}
+
+/// The observers of every event type
+#[derive(Default)]
+pub(crate) struct Observers {
+    /// Maps the type of an event to a `Vec<ObserverEntry<E>>`
+    by_event: HashMap<TypeId, Box<dyn Any + Send>>,
+}
+
+impl Observers {
+    pub(crate) fn add<E: Event>(
+        &mut self,
+        observer: Box<dyn Observer<E>>,
+        entity: Option<EntityId>,
+    ) {
+        self.entries_mut::<E>()
+            .push(ObserverEntry { observer, entity });
+    }
+
+    /// Takes the observers of `E` out, so they can run with access to the rest of the world.
+    /// They have to be given back with `Observers::restore`.
+    pub(crate) fn take<E: Event>(&mut self) -> Vec<ObserverEntry<E>> {
+        std::mem::take(self.entries_mut::<E>())
+    }
+
+    pub(crate) fn restore<E: Event>(&mut self, mut entries: Vec<ObserverEntry<E>>) {
+        let current = self.entries_mut::<E>();
+        entries.append(current);
+        *current = entries;
+    }
+
+    fn entries_mut<E: Event>(&mut self) -> &mut Vec<ObserverEntry<E>> {
+        self.by_event
+            .entry(TypeId::of::<E>())
+            .or_insert_with(|| Box::new(Vec::<ObserverEntry<E>>::new()))
+            .downcast_mut()
+            .unwrap()
+    }
+}
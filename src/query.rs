@@ -1,34 +1,77 @@
-use std::marker::PhantomData;
+use std::{
+    fmt,
+    marker::PhantomData,
+    sync::atomic::{self, AtomicUsize},
+    thread,
+};
 
 use crate::{
     SystemWorldArgs,
-    component::ComponentManager,
-    entity::{ComponentColumns, EntityBitmask, EntityId},
-    system::{SafetyInfo, SystemParam},
+    change_detection::{Mut, SystemTicks},
+    component::{Component, ComponentManager},
+    entity::{
+        Archetype, ArchetypeId, ComponentColumns, EntityBitmask, EntityId, EntityLocation,
+        EntityManager, column_of,
+    },
+    system::{ReadOnlySystemParam, SafetyInfo, SystemParam, SystemRunArgs, available_threads},
 };
 
-pub(crate) struct QueryInfo {
+#[derive(Default, Clone)]
+pub struct QueryInfo {
+    /// The components an entity must have to match the query
     pub(crate) query_bitmask: EntityBitmask,
+    /// The components accessed through `Option`, which do not affect matching
+    pub(crate) optional_bitmask: EntityBitmask,
+    /// The components whose ticks are checked by `Added` and `Changed` filters
+    pub(crate) filtered_bitmask: EntityBitmask,
     pub(crate) restrictions_bitmask: EntityBitmask,
+    /// The queried components that are accessed through `&mut`
+    pub(crate) mutable_bitmask: EntityBitmask,
 }
 
 impl QueryInfo {
-    pub(crate) fn from_query<V: QueryBundle, R: QueryBundle>(
+    pub(crate) fn from_query<V: QueryBundle, R: QueryRestrictions>(
         components_manager: &mut ComponentManager,
     ) -> Self {
-        let query_bitmask = V::into_bitmask(components_manager);
-        let restrictions_bitmask = R::into_bitmask(components_manager);
-        let new_info = Self {
-            query_bitmask,
-            restrictions_bitmask,
-        };
+        let mut new_info = Self::default();
+        R::register(components_manager, &mut new_info);
+        V::register(components_manager, &mut new_info);
         debug_assert!(
             new_info
-                .query_bitmask
+                .required_bitmask()
                 .is_disjoint(&new_info.restrictions_bitmask)
         );
         new_info
     }
+
+    /// The components an entity must have to match the query, including the filtered ones
+    pub(crate) fn required_bitmask(&self) -> EntityBitmask {
+        self.query_bitmask.union(&self.filtered_bitmask).collect()
+    }
+
+    /// Whether the two queries can never match the same entity
+    pub(crate) fn is_disjoint(&self, other: &Self) -> bool {
+        !self
+            .required_bitmask()
+            .is_disjoint(&other.restrictions_bitmask)
+            || !other
+                .required_bitmask()
+                .is_disjoint(&self.restrictions_bitmask)
+    }
+
+    fn accessed_bitmask(&self) -> EntityBitmask {
+        self.required_bitmask()
+            .union(&self.optional_bitmask)
+            .collect()
+    }
+
+    /// Returns a component that one query writes and the other accesses, if any
+    pub(crate) fn conflicting_component(&self, other: &Self) -> Option<usize> {
+        self.mutable_bitmask
+            .intersection(&other.accessed_bitmask())
+            .chain(other.mutable_bitmask.intersection(&self.accessed_bitmask()))
+            .next()
+    }
 }
 
 #[derive(Debug)]
@@ -46,59 +89,475 @@ impl<ResultType> From<(EntityId, ResultType)> for QueryResult<ResultType> {
     }
 }
 
-pub struct Query<'a, Values: QueryBundle, Restrictions: QueryBundle> {
-    pub results: Box<[QueryResult<Values::ResultType<'a>>]>,
-    _restrictions: PhantomData<Restrictions>,
+/// Why `Query::get` and its variants could not return the components of an entity
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryEntityError {
+    /// The entity was despawned, or the id is stale
+    NoSuchEntity(EntityId),
+    /// The entity exists but lacks a queried component or has a restricted one
+    QueryDoesNotMatch(EntityId),
+    /// The same entity was requested more than once through mutable access
+    AliasedMutability(EntityId),
 }
 
-impl<'a, Values: QueryBundle, Restrictions: QueryBundle> Query<'a, Values, Restrictions> {
-    fn new(results: Box<[QueryResult<Values::ResultType<'a>>]>) -> Self {
+impl fmt::Display for QueryEntityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoSuchEntity(entity) => write!(f, "Entity {entity} does not exist"),
+            Self::QueryDoesNotMatch(entity) => {
+                write!(f, "Entity {entity} does not match the query")
+            }
+            Self::AliasedMutability(entity) => {
+                write!(f, "Entity {entity} was requested mutably more than once")
+            }
+        }
+    }
+}
+
+impl std::error::Error for QueryEntityError {}
+
+/// An archetype matched by a query, with the columns of the queried and filtered components
+pub(crate) struct MatchedArchetype {
+    pub(crate) archetype: ArchetypeId,
+    pub(crate) order: ComponentOrder,
+    filter_order: ComponentOrder,
+}
+
+/// Checks the row filters of a query against an entity of a matched archetype
+type RowFilter = unsafe fn(*const ComponentColumns, &ComponentOrder, usize, SystemTicks) -> bool;
+
+/// The part of a query that is kept by its system between runs
+pub struct QueryState {
+    pub(crate) info: QueryInfo,
+    into_order: fn(&ComponentManager, &EntityBitmask) -> ComponentOrder,
+    filter_into_order: fn(&ComponentManager, &EntityBitmask) -> ComponentOrder,
+    /// `None` if the restrictions only depend on the archetype
+    row_filter: Option<RowFilter>,
+    pub(crate) matched: Vec<MatchedArchetype>,
+    /// For every archetype checked against the query so far, its index in `matched` if it
+    /// matched. Archetypes are never removed, so only the ones after this need to be checked.
+    archetype_matches: Vec<Option<usize>>,
+}
+
+impl QueryState {
+    pub(crate) fn new<V: QueryBundle, R: QueryRestrictions>(
+        components_manager: &mut ComponentManager,
+    ) -> Self {
         Self {
-            results,
-            _restrictions: PhantomData,
+            info: QueryInfo::from_query::<V, R>(components_manager),
+            into_order: V::into_order,
+            filter_into_order: R::into_order,
+            row_filter: R::FILTERS_ROWS.then_some(R::matches as RowFilter),
+            matched: Vec::new(),
+            archetype_matches: Vec::new(),
+        }
+    }
+
+    /// Matches the archetypes created since the last update
+    pub(crate) fn update_archetypes(
+        &mut self,
+        entity_manager: &EntityManager,
+        components_manager: &ComponentManager,
+    ) {
+        for (id, archetype) in entity_manager
+            .archetypes
+            .iter()
+            .enumerate()
+            .skip(self.archetype_matches.len())
+        {
+            if archetype.bitmask.matches_query(
+                &self.info.required_bitmask(),
+                &self.info.restrictions_bitmask,
+            ) {
+                self.archetype_matches.push(Some(self.matched.len()));
+                self.matched.push(MatchedArchetype {
+                    archetype: id,
+                    order: (self.into_order)(components_manager, &archetype.bitmask),
+                    filter_order: (self.filter_into_order)(components_manager, &archetype.bitmask),
+                });
+            } else {
+                self.archetype_matches.push(None);
+            }
         }
     }
+
+    /// Whether the entity in the row of a matched archetype passes the row filters
+    /// SAFETY: The archetype must be the matched one, and its ticks must not be borrowed mutably
+    unsafe fn matches_row(
+        &self,
+        archetype: *const Archetype,
+        matched: &MatchedArchetype,
+        row: usize,
+        ticks: SystemTicks,
+    ) -> bool {
+        self.row_filter.is_none_or(|filter| unsafe {
+            filter(
+                &raw const (*archetype).component_columns,
+                &matched.filter_order,
+                row,
+                ticks,
+            )
+        })
+    }
+
+    fn matched_archetype(&self, archetype: ArchetypeId) -> Option<&MatchedArchetype> {
+        self.archetype_matches
+            .get(archetype)
+            .copied()
+            .flatten()
+            .map(|index| &self.matched[index])
+    }
 }
 
-impl<'a, Values: QueryBundle, Restrictions: QueryBundle> SystemParam
-    for Query<'a, Values, Restrictions>
-{
-    /// SAFETY: Cannot have two queries with the same component at the same time or multiple mutable references to the same value is possible.
-    unsafe fn init(args: *mut crate::SystemWorldArgs) -> Self {
-        let info: QueryInfo =
-            QueryInfo::from_query::<Values, Restrictions>(unsafe { (*args).components_manager });
-        // NOTE: The results are ordered by component_id
-        let archetypes = unsafe {
-            (*args)
-                .entity_manager
-                .query(&info.query_bitmask, &info.restrictions_bitmask)
+/// Gives access to the entities matching `Values` that have none of the `Restrictions`.
+/// The matched archetypes are walked lazily by `iter` and `iter_mut`.
+pub struct Query<'a, Values: QueryBundle, Restrictions: QueryRestrictions> {
+    state: &'a QueryState,
+    entity_manager: *const EntityManager,
+    /// The archetypes of the entity manager, which cannot change while the query is alive. Only
+    /// shared references to them are created: components are written through the pointers of
+    /// `ComponentColumns`, since other systems may be reading other columns of an archetype.
+    archetypes: *const Archetype,
+    ticks: SystemTicks,
+    _marker: PhantomData<(&'a mut Archetype, Values, Restrictions)>,
+}
+
+impl<'a, Values: QueryBundle, Restrictions: QueryRestrictions> Query<'a, Values, Restrictions> {
+    /// SAFETY: The state must be up to date with the entity manager.
+    /// Cannot have two queries with the same component at the same time or multiple mutable references to the same value is possible.
+    pub(crate) unsafe fn new(
+        state: &'a QueryState,
+        entity_manager: *const EntityManager,
+        ticks: SystemTicks,
+    ) -> Self {
+        Self {
+            state,
+            entity_manager,
+            archetypes: unsafe { (*entity_manager).archetypes.as_ptr() },
+            ticks,
+            _marker: PhantomData,
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn from_world(
+        world: &'a mut crate::World,
+        state: &'a mut Option<QueryState>,
+    ) -> Self {
+        let state = state.get_or_insert_with(|| {
+            QueryState::new::<Values, Restrictions>(&mut world.components_manager)
+        });
+        state.update_archetypes(&world.entity_manager, &world.components_manager);
+        let ticks = SystemTicks {
+            last_run: 0,
+            this_run: world.entity_manager.increment_change_tick(),
         };
+        unsafe { Self::new(state, &world.entity_manager, ticks) }
+    }
+
+    /// Iterates over the matched entities with shared access to every component
+    pub fn iter(&self) -> QueryIter<'_, Values::ReadOnly> {
+        QueryIter::new(self.archetypes, self.state, self.ticks)
+    }
+
+    pub fn iter_mut(&mut self) -> QueryIter<'_, Values> {
+        QueryIter::new(self.archetypes, self.state, self.ticks)
+    }
+
+    /// Returns the components of `entity` with shared access
+    pub fn get(
+        &self,
+        entity: EntityId,
+    ) -> Result<<Values::ReadOnly as QueryBundle>::ResultType<'_>, QueryEntityError> {
+        let (location, matched) = self.matched_location(entity)?;
+        Ok(unsafe { self.fetch::<Values::ReadOnly>(location, matched) })
+    }
 
-        let result = archetypes
-            .into_vec()
-            .into_iter()
-            .flat_map(|(bitmask, archetype)| {
-                let archetype_order =
-                    Values::into_order(unsafe { (*args).components_manager }, bitmask);
-                archetype
-                    .entities
-                    .iter()
-                    .enumerate()
-                    .map(|(i, &entity)| QueryResult {
-                        entity,
-                        components: unsafe {
-                            Values::from_columns(
-                                i,
-                                &archetype_order,
-                                &mut archetype.component_columns as *mut ComponentColumns,
-                            )
-                        },
-                    })
-                    .collect::<Vec<_>>()
+    pub fn get_mut(
+        &mut self,
+        entity: EntityId,
+    ) -> Result<Values::ResultType<'_>, QueryEntityError> {
+        let (location, matched) = self.matched_location(entity)?;
+        Ok(unsafe { self.fetch::<Values>(location, matched) })
+    }
+
+    /// Returns the components of several entities at once.
+    /// Fails with `QueryEntityError::AliasedMutability` if an entity is passed more than once.
+    pub fn get_many_mut<const N: usize>(
+        &mut self,
+        entities: [EntityId; N],
+    ) -> Result<[Values::ResultType<'_>; N], QueryEntityError> {
+        let mut locations = [None; N];
+        for (i, &entity) in entities.iter().enumerate() {
+            if entities[..i].contains(&entity) {
+                return Err(QueryEntityError::AliasedMutability(entity));
+            }
+            locations[i] = Some(self.matched_location(entity)?);
+        }
+        // SAFETY: The entities are disjoint, so no component is borrowed twice
+        Ok(locations.map(|location| {
+            let (location, matched) = location.unwrap();
+            unsafe { self.fetch::<Values>(location, matched) }
+        }))
+    }
+
+    /// Whether `entity` exists and matches the query
+    pub fn contains(&self, entity: EntityId) -> bool {
+        self.matched_location(entity).is_ok()
+    }
+
+    fn matched_location(
+        &self,
+        entity: EntityId,
+    ) -> Result<(EntityLocation, &MatchedArchetype), QueryEntityError> {
+        let location = unsafe { (*self.entity_manager).location(&entity) }
+            .ok_or(QueryEntityError::NoSuchEntity(entity))?;
+        let matched = self
+            .state
+            .matched_archetype(location.archetype)
+            .filter(|matched| unsafe {
+                let archetype = self.archetypes.add(location.archetype);
+                self.state
+                    .matches_row(archetype, matched, location.row, self.ticks)
             })
-            .collect();
+            .ok_or(QueryEntityError::QueryDoesNotMatch(entity))?;
+        Ok((location, matched))
+    }
+
+    /// SAFETY: The returned components must not be borrowed mutably anywhere else
+    unsafe fn fetch<'q, V: QueryBundle>(
+        &'q self,
+        location: EntityLocation,
+        matched: &MatchedArchetype,
+    ) -> V::ResultType<'q> {
+        let archetype = unsafe { self.archetypes.add(location.archetype) };
+        unsafe {
+            V::from_columns(
+                location.row,
+                &matched.order,
+                &raw const (*archetype).component_columns,
+                self.ticks,
+            )
+        }
+    }
+
+    /// Calls `f` on every matched entity with shared access to every component. The entities
+    /// are split into batches of `batch_size` that are spread over multiple threads.
+    /// # Panics
+    /// If `batch_size` is zero
+    pub fn par_for_each<'q>(
+        &'q self,
+        batch_size: usize,
+        f: impl Fn(QueryResult<<Values::ReadOnly as QueryBundle>::ResultType<'q>>) + Sync,
+    ) {
+        // SAFETY: Shared access cannot alias a mutable reference
+        unsafe { self.par_for_each_unchecked::<Values::ReadOnly>(batch_size, f) }
+    }
+
+    /// Calls `f` on every matched entity, like `par_for_each` but with mutable access
+    /// # Panics
+    /// If `batch_size` is zero
+    pub fn par_for_each_mut<'q>(
+        &'q mut self,
+        batch_size: usize,
+        f: impl Fn(QueryResult<Values::ResultType<'q>>) + Sync,
+    ) {
+        // SAFETY: Every entity is fetched once, by a single thread
+        unsafe { self.par_for_each_unchecked::<Values>(batch_size, f) }
+    }
+
+    /// SAFETY: The components fetched must not be borrowed mutably anywhere else
+    unsafe fn par_for_each_unchecked<'q, V: QueryBundle>(
+        &'q self,
+        batch_size: usize,
+        f: impl Fn(QueryResult<V::ResultType<'q>>) + Sync,
+    ) {
+        assert!(batch_size > 0, "The batch size must not be zero");
+        let archetypes = SharedArchetypes(self.archetypes);
+        let ticks = self.ticks;
+        let mut batches = Vec::new();
+        for matched in &self.state.matched {
+            let len = unsafe { (*self.archetypes.add(matched.archetype)).entities.len() };
+            for start in (0..len).step_by(batch_size) {
+                batches.push((matched, start..len.min(start + batch_size)));
+            }
+        }
+
+        let next_batch = AtomicUsize::new(0);
+        let run_batches = || {
+            while let Some((matched, rows)) =
+                batches.get(next_batch.fetch_add(1, atomic::Ordering::Relaxed))
+            {
+                // SAFETY: Batches never overlap, and the archetypes cannot change while the
+                // query is alive. Threads working on the same archetype only share references to
+                // it, and write through the pointers to the values and ticks of their own rows.
+                let archetype = unsafe { archetypes.get().add(matched.archetype) };
+                for row in rows.clone() {
+                    if !unsafe { self.state.matches_row(archetype, matched, row, ticks) } {
+                        continue;
+                    }
+                    let result = unsafe {
+                        QueryResult {
+                            entity: (&(*archetype).entities)[row],
+                            components: V::from_columns(
+                                row,
+                                &matched.order,
+                                &raw const (*archetype).component_columns,
+                                ticks,
+                            ),
+                        }
+                    };
+                    f(result);
+                }
+            }
+        };
+
+        // A single batch, or a single thread, is not worth spawning threads for
+        let threads = available_threads().min(batches.len());
+        if threads <= 1 {
+            run_batches();
+            return;
+        }
+        thread::scope(|scope| {
+            for _ in 1..threads {
+                scope.spawn(run_batches);
+            }
+            run_batches();
+        });
+    }
+
+    /// Collects every matched entity into a slice, for when indexing is needed.
+    /// Prefer `iter` and `iter_mut`, which do not allocate.
+    pub fn results(&mut self) -> Box<[QueryResult<Values::ResultType<'_>>]> {
+        self.iter_mut().collect()
+    }
+
+    /// The number of matched entities. Has to check every entity if the query filters them by
+    /// their ticks.
+    pub fn len(&self) -> usize {
+        if self.state.row_filter.is_some() {
+            return self.iter().count();
+        }
+        self.state
+            .matched
+            .iter()
+            .map(|matched| unsafe { (*self.archetypes.add(matched.archetype)).entities.len() })
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<'q, Values: QueryBundle, Restrictions: QueryRestrictions> IntoIterator
+    for &'q Query<'_, Values, Restrictions>
+{
+    type Item = QueryResult<<Values::ReadOnly as QueryBundle>::ResultType<'q>>;
+    type IntoIter = QueryIter<'q, Values::ReadOnly>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'q, Values: QueryBundle, Restrictions: QueryRestrictions> IntoIterator
+    for &'q mut Query<'_, Values, Restrictions>
+{
+    type Item = QueryResult<Values::ResultType<'q>>;
+    type IntoIter = QueryIter<'q, Values>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter_mut()
+    }
+}
+
+/// The archetypes of the entity manager, shared by the threads of a parallel iteration
+struct SharedArchetypes(*const Archetype);
+
+// SAFETY: Components are `Send + Sync`, and every thread works on different entities
+unsafe impl Send for SharedArchetypes {}
+unsafe impl Sync for SharedArchetypes {}
+
+impl SharedArchetypes {
+    fn get(&self) -> *const Archetype {
+        self.0
+    }
+}
+
+pub struct QueryIter<'q, Values: QueryBundle> {
+    archetypes: *const Archetype,
+    state: &'q QueryState,
+    ticks: SystemTicks,
+    matched: std::slice::Iter<'q, MatchedArchetype>,
+    current: Option<&'q MatchedArchetype>,
+    row: usize,
+    _values: PhantomData<Values>,
+}
 
-        Self::new(result)
+impl<'q, Values: QueryBundle> QueryIter<'q, Values> {
+    fn new(archetypes: *const Archetype, state: &'q QueryState, ticks: SystemTicks) -> Self {
+        Self {
+            archetypes,
+            state,
+            ticks,
+            matched: state.matched.iter(),
+            current: None,
+            row: 0,
+            _values: PhantomData,
+        }
+    }
+}
+
+impl<'q, Values: QueryBundle> Iterator for QueryIter<'q, Values> {
+    type Item = QueryResult<Values::ResultType<'q>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(matched) = self.current {
+                // SAFETY: The archetypes cannot change while a system runs, and the query borrow
+                // this iterator holds rules out overlapping mutable access
+                let archetype = unsafe { self.archetypes.add(matched.archetype) };
+                let entities = unsafe { &(*archetype).entities };
+                while let Some(&entity) = entities.get(self.row) {
+                    let row = self.row;
+                    self.row += 1;
+                    if !unsafe { self.state.matches_row(archetype, matched, row, self.ticks) } {
+                        continue;
+                    }
+                    let components = unsafe {
+                        Values::from_columns(
+                            row,
+                            &matched.order,
+                            &raw const (*archetype).component_columns,
+                            self.ticks,
+                        )
+                    };
+                    return Some(QueryResult { entity, components });
+                }
+            }
+            self.current = Some(self.matched.next()?);
+            self.row = 0;
+        }
+    }
+}
+
+impl<'a, Values: QueryBundle, Restrictions: QueryRestrictions> SystemParam
+    for Query<'a, Values, Restrictions>
+{
+    type State = QueryState;
+
+    fn init_state(args: &mut SystemWorldArgs) -> Self::State {
+        QueryState::new::<Values, Restrictions>(args.components_manager)
+    }
+
+    /// SAFETY: Cannot have two queries with the same component at the same time or multiple mutable references to the same value is possible.
+    unsafe fn init(state: *mut Self::State, args: SystemRunArgs) -> Self {
+        let world = args.world;
+        unsafe {
+            (*state).update_archetypes(&*world.entity_manager, &*world.components_manager);
+            Self::new(&*state, world.entity_manager, args.ticks)
+        }
     }
 
     fn safety_info(args: &mut SystemWorldArgs) -> Option<SafetyInfo> {
@@ -109,10 +568,100 @@ impl<'a, Values: QueryBundle, Restrictions: QueryBundle> SystemParam
     }
 }
 
-type ComponentOrder = Box<[usize]>;
+impl<Values, Restrictions> ReadOnlySystemParam for Query<'_, Values, Restrictions>
+where
+    Values: QueryBundle<ReadOnly = Values>,
+    Restrictions: QueryRestrictions,
+{
+}
+
+/// For every term of a query, the column of its component in an archetype, or `None` if the
+/// archetype lacks an optional component
+type ComponentOrder = Box<[Option<usize>]>;
+
+/// A single element of a query: `&C` for shared access, `&mut C` for mutable access, or either
+/// wrapped in an `Option` for components the entity may lack
+pub trait QueryTerm {
+    type Component: Component;
+    type Item<'a>;
+    /// The same term with shared access only
+    type ReadOnly: QueryTerm<Component = Self::Component>;
+    const MUTABLE: bool;
+    const REQUIRED: bool = true;
+    /// SAFETY: Cannot have two queries with the same component at the same time if one of them
+    /// is mutable.
+    unsafe fn fetch<'a>(
+        columns: *const ComponentColumns,
+        column: Option<usize>,
+        index: usize,
+        ticks: SystemTicks,
+    ) -> Self::Item<'a>;
+}
+
+impl<C: Component> QueryTerm for &C {
+    type Component = C;
+    type Item<'a> = &'a C;
+    type ReadOnly = Self;
+    const MUTABLE: bool = false;
+
+    unsafe fn fetch<'a>(
+        columns: *const ComponentColumns,
+        column: Option<usize>,
+        index: usize,
+        _: SystemTicks,
+    ) -> Self::Item<'a> {
+        unsafe { &*(*columns).value_ptr::<C>(column.unwrap(), index) }
+    }
+}
+
+impl<C: Component> QueryTerm for &mut C {
+    type Component = C;
+    type Item<'a> = Mut<'a, C>;
+    type ReadOnly = &'static C;
+    const MUTABLE: bool = true;
+
+    unsafe fn fetch<'a>(
+        columns: *const ComponentColumns,
+        column: Option<usize>,
+        index: usize,
+        ticks: SystemTicks,
+    ) -> Self::Item<'a> {
+        let column = column.unwrap();
+        // SAFETY: No mutable reference to the columns is created, since other threads may be
+        // writing to other rows
+        let (value, component_ticks) = unsafe {
+            (
+                &mut *(*columns).value_ptr::<C>(column, index),
+                &mut *(*columns).ticks_ptr(column, index),
+            )
+        };
+        Mut::new(value, component_ticks, ticks)
+    }
+}
+
+impl<T: QueryTerm> QueryTerm for Option<T> {
+    type Component = T::Component;
+    type Item<'a> = Option<T::Item<'a>>;
+    type ReadOnly = Option<T::ReadOnly>;
+    const MUTABLE: bool = T::MUTABLE;
+    const REQUIRED: bool = false;
+
+    unsafe fn fetch<'a>(
+        columns: *const ComponentColumns,
+        column: Option<usize>,
+        index: usize,
+        ticks: SystemTicks,
+    ) -> Self::Item<'a> {
+        column.map(|column| unsafe { T::fetch(columns, Some(column), index, ticks) })
+    }
+}
+
 pub trait QueryBundle {
     type ResultType<'a>;
-    fn into_bitmask(component_manager: &mut ComponentManager) -> EntityBitmask;
+    /// The same bundle with shared access to every component
+    type ReadOnly: QueryBundle;
+    /// Registers the queried components in the info's bitmasks
+    fn register(component_manager: &mut ComponentManager, info: &mut QueryInfo);
     // NOTE: it is assumed that every component already exists when this function is called
     fn into_order(
         component_manager: &ComponentManager,
@@ -122,42 +671,142 @@ pub trait QueryBundle {
     unsafe fn from_columns<'a>(
         index: usize,
         archetype_order: &ComponentOrder,
-        columns: *mut ComponentColumns,
+        columns: *const ComponentColumns,
+        ticks: SystemTicks,
     ) -> Self::ResultType<'a>;
 }
 
-macro_rules! impl_query_bundle {
-    ($(($n:tt, $Q:ident)),*) => {
-        impl<$($Q: crate::component::Component),*> QueryBundle for ($($Q,)*) {
-            type ResultType<'a> = ($(&'a mut $Q,)*);
-            #[allow(unused_assignments, unused_variables, unused_mut)]
-            fn into_bitmask(component_manager: &mut ComponentManager) -> EntityBitmask {
-                let mut bitset = bit_set::BitSet::new();
+/// The filters of a query: a tuple of `QueryFilter`s that must all pass for an entity to match
+pub trait QueryRestrictions {
+    /// Whether some filter has to check every entity rather than only its archetype
+    const FILTERS_ROWS: bool;
+    /// Registers the filtered components in the info's bitmasks
+    fn register(component_manager: &mut ComponentManager, info: &mut QueryInfo);
+    // NOTE: it is assumed that every component already exists when this function is called
+    fn into_order(
+        component_manager: &ComponentManager,
+        other_bitmask: &EntityBitmask,
+    ) -> ComponentOrder;
+    /// SAFETY: The ticks of the filtered components must not be borrowed mutably
+    unsafe fn matches(
+        columns: *const ComponentColumns,
+        archetype_order: &ComponentOrder,
+        index: usize,
+        ticks: SystemTicks,
+    ) -> bool;
+}
 
-                $(
-                    let id = component_manager.register_component_if_not_exists::<$Q>();
-                    let had_inserted = bitset.insert(id);
-                    debug_assert!(had_inserted, "duplicate component type in query");
-                )*
+/// A single filter of a query: a bare component `C` excludes the entities that have it, while
+/// `Added<C>` and `Changed<C>` only keep the entities whose `C` was added or changed since the
+/// system last ran
+pub trait QueryFilter {
+    const FILTERS_ROWS: bool;
+    fn register(component_manager: &mut ComponentManager, info: &mut QueryInfo);
+    /// The column whose ticks the filter checks, if it checks any
+    fn column(component_manager: &ComponentManager, other_bitmask: &EntityBitmask)
+    -> Option<usize>;
+    /// SAFETY: The ticks of the column must not be borrowed mutably
+    unsafe fn matches(
+        columns: *const ComponentColumns,
+        column: Option<usize>,
+        index: usize,
+        ticks: SystemTicks,
+    ) -> bool;
+}
 
-                bitset.into()
+impl<C: Component> QueryFilter for C {
+    const FILTERS_ROWS: bool = false;
+
+    fn register(component_manager: &mut ComponentManager, info: &mut QueryInfo) {
+        let id = component_manager.register_component_if_not_exists::<C>();
+        info.restrictions_bitmask.insert(id);
+    }
+
+    fn column(_: &ComponentManager, _: &EntityBitmask) -> Option<usize> {
+        None
+    }
+
+    unsafe fn matches(
+        _: *const ComponentColumns,
+        _: Option<usize>,
+        _: usize,
+        _: SystemTicks,
+    ) -> bool {
+        true
+    }
+}
+
+/// Only matches the entities whose `C` was added since the system last ran
+pub struct Added<C: Component>(PhantomData<C>);
+
+/// Only matches the entities whose `C` was added or changed since the system last ran.
+/// Writing to a component through a `Mut` marks it as changed.
+pub struct Changed<C: Component>(PhantomData<C>);
+
+macro_rules! impl_tick_filter {
+    ($filter:ident, $tick:ident) => {
+        impl<C: Component> QueryFilter for $filter<C> {
+            const FILTERS_ROWS: bool = true;
+
+            fn register(component_manager: &mut ComponentManager, info: &mut QueryInfo) {
+                let id = component_manager.register_component_if_not_exists::<C>();
+                info.filtered_bitmask.insert(id);
             }
 
-            #[allow(unused_variables, unused_mut)]
-            fn into_order(component_manager: &ComponentManager, other_bitmask: &EntityBitmask) -> ComponentOrder {
-                // TODO: use corret size instead of vector
-                let mut order = Vec::new();
-                let mut iterator = other_bitmask.iter().enumerate();
+            fn column(
+                component_manager: &ComponentManager,
+                other_bitmask: &EntityBitmask,
+            ) -> Option<usize> {
+                column_of(
+                    other_bitmask,
+                    component_manager.get_component_id::<C>().unwrap(),
+                )
+            }
+
+            unsafe fn matches(
+                columns: *const ComponentColumns,
+                column: Option<usize>,
+                index: usize,
+                ticks: SystemTicks,
+            ) -> bool {
+                let component_ticks = unsafe { *(*columns).ticks_ptr(column.unwrap(), index) };
+                ticks.is_new(component_ticks.$tick)
+            }
+        }
+    };
+}
+
+impl_tick_filter!(Added, added);
+impl_tick_filter!(Changed, changed);
+
+macro_rules! impl_query_bundle {
+    ($(($n:tt, $Q:ident)),*) => {
+        impl<$($Q: QueryTerm),*> QueryBundle for ($($Q,)*) {
+            type ResultType<'a> = ($($Q::Item<'a>,)*);
+            type ReadOnly = ($($Q::ReadOnly,)*);
+            #[allow(unused_variables)]
+            fn register(component_manager: &mut ComponentManager, info: &mut QueryInfo) {
                 $({
-                    let current_id = component_manager.get_component_id::<$Q>().unwrap();
-                    while let Some((i, id)) = iterator.next() {
-                        if current_id == id {
-                            order.push(i);
-                            break;
-                        }
+                    let id = component_manager.register_component_if_not_exists::<$Q::Component>();
+                    let duplicate = info.query_bitmask.contains(id) || info.optional_bitmask.contains(id);
+                    assert!(!duplicate, "duplicate component type in query");
+
+                    if $Q::REQUIRED {
+                        info.query_bitmask.insert(id);
+                    } else {
+                        info.optional_bitmask.insert(id);
+                    }
+                    if $Q::MUTABLE {
+                        info.mutable_bitmask.insert(id);
                     }
                 })*
-                order.into_boxed_slice()
+            }
+
+            #[allow(unused_variables)]
+            fn into_order(component_manager: &ComponentManager, other_bitmask: &EntityBitmask) -> ComponentOrder {
+                Box::new([$(
+                    column_of(other_bitmask, component_manager.get_component_id::<$Q::Component>().unwrap())
+                ,)*])
             }
 
             #[allow(clippy::unused_unit)]
@@ -165,10 +814,11 @@ macro_rules! impl_query_bundle {
             unsafe fn from_columns<'a>(
                 index: usize,
                 archetype_order: &ComponentOrder,
-                columns: *mut ComponentColumns,
+                columns: *const ComponentColumns,
+                ticks: SystemTicks,
             ) -> Self::ResultType<'a> {
                 ($(
-                    unsafe { (*columns).get_mut_from_column::<$Q>(archetype_order[$n], index).unwrap() }
+                    unsafe { $Q::fetch(columns, archetype_order[$n], index, ticks) }
                 ,)*)
             }
         }
@@ -176,3 +826,33 @@ macro_rules! impl_query_bundle {
 }
 
 variadics_please::all_tuples_enumerated!(impl_query_bundle, 0, 15, B);
+
+macro_rules! impl_query_restrictions {
+    ($(($n:tt, $R:ident)),*) => {
+        impl<$($R: QueryFilter),*> QueryRestrictions for ($($R,)*) {
+            const FILTERS_ROWS: bool = false $(|| $R::FILTERS_ROWS)*;
+
+            #[allow(unused_variables)]
+            fn register(component_manager: &mut ComponentManager, info: &mut QueryInfo) {
+                $($R::register(component_manager, info);)*
+            }
+
+            #[allow(unused_variables)]
+            fn into_order(component_manager: &ComponentManager, other_bitmask: &EntityBitmask) -> ComponentOrder {
+                Box::new([$($R::column(component_manager, other_bitmask),)*])
+            }
+
+            #[allow(unused_variables)]
+            unsafe fn matches(
+                columns: *const ComponentColumns,
+                archetype_order: &ComponentOrder,
+                index: usize,
+                ticks: SystemTicks,
+            ) -> bool {
+                true $(&& unsafe { $R::matches(columns, archetype_order[$n], index, ticks) })*
+            }
+        }
+    };
+}
+
+variadics_please::all_tuples_enumerated!(impl_query_restrictions, 0, 15, R);
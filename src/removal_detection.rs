@@ -0,0 +1,89 @@
+use std::marker::PhantomData;
+
+use crate::{
+    Component, EntityId, SystemWorldArgs,
+    component::ComponentId,
+    event::{Event, Events},
+    system::{ReadOnlySystemParam, SafetyInfo, SystemParam, SystemRunArgs},
+};
+
+/// An entity that lost a component, by despawning or by having it removed
+#[derive(Debug)]
+pub(crate) struct RemovedEntity(pub(crate) EntityId);
+
+impl Event for RemovedEntity {}
+
+/// The entities that lost the component `C` since the system last ran, whether they were
+/// despawned or had `C` removed. Like events, removals are only kept for two updates. Removals
+/// of `C` are only recorded once a system reading them has been added.
+pub struct RemovedComponents<'a, C: Component> {
+    cursor: &'a mut usize,
+    /// `None` if no removal of the component was ever recorded
+    removed: Option<&'a Events<RemovedEntity>>,
+    _marker: PhantomData<C>,
+}
+
+impl<C: Component> RemovedComponents<'_, C> {
+    /// Iterates over the entities that lost the component, oldest first, marking them as read
+    pub fn read(&mut self) -> impl Iterator<Item = EntityId> {
+        let cursor = &mut *self.cursor;
+        self.removed
+            .map(|removed| removed.read(cursor))
+            .into_iter()
+            .flatten()
+            .map(|removed| removed.0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.removed
+            .map_or(0, |removed| removed.read_from(*self.cursor).count())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+pub struct RemovedComponentsState {
+    component: ComponentId,
+    cursor: usize,
+}
+
+impl<C: Component> SystemParam for RemovedComponents<'_, C> {
+    type State = RemovedComponentsState;
+
+    fn init_state(args: &mut SystemWorldArgs) -> Self::State {
+        RemovedComponentsState {
+            component: track_removed::<C>(args),
+            cursor: 0,
+        }
+    }
+
+    /// SAFETY: Removals are only recorded while commands are applied, when no system runs.
+    unsafe fn init(state: *mut Self::State, args: SystemRunArgs) -> Self {
+        let state = unsafe { &mut *state };
+        let entity_manager = unsafe { &*args.world.entity_manager };
+        Self {
+            cursor: &mut state.cursor,
+            removed: entity_manager.removed(state.component),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Starts recording the removals as soon as the system is added, rather than when it first
+    /// runs
+    fn safety_info(args: &mut SystemWorldArgs) -> Option<SafetyInfo> {
+        track_removed::<C>(args);
+        None
+    }
+}
+
+fn track_removed<C: Component>(args: &mut SystemWorldArgs) -> ComponentId {
+    let component = args
+        .components_manager
+        .register_component_if_not_exists::<C>();
+    args.entity_manager.track_removed(component);
+    component
+}
+
+impl<C: Component> ReadOnlySystemParam for RemovedComponents<'_, C> {}
@@ -0,0 +1,163 @@
+use std::{
+    any::{Any, TypeId},
+    cell::UnsafeCell,
+    collections::HashMap,
+    ops::{Deref, DerefMut},
+};
+
+use crate::{
+    SystemWorldArgs,
+    system::{ReadOnlySystemParam, SafetyInfo, SystemParam, SystemRunArgs},
+};
+
+/// A global, unique value stored in the `World` rather than in an entity
+pub trait Resource: Send + Sync + 'static {}
+
+/// Lets a system write to its resource through a shared reference to the manager, since other
+/// systems may be reading different resources at the same time
+struct ResourceCell<R>(UnsafeCell<R>);
+
+// SAFETY: Resources are `Send + Sync`, and the executor never runs a system writing to a
+// resource alongside another system accessing it
+unsafe impl<R: Resource> Sync for ResourceCell<R> {}
+
+#[derive(Default)]
+pub struct ResourceManager {
+    resources: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl ResourceManager {
+    pub(crate) fn insert<R: Resource>(&mut self, resource: R) -> Option<R> {
+        self.resources
+            .insert(
+                TypeId::of::<R>(),
+                Box::new(ResourceCell(UnsafeCell::new(resource))),
+            )
+            .map(|previous| {
+                previous
+                    .downcast::<ResourceCell<R>>()
+                    .unwrap()
+                    .0
+                    .into_inner()
+            })
+    }
+
+    pub(crate) fn get<R: Resource>(&self) -> Option<&R> {
+        // SAFETY: Resources are only written through a shared reference by `ResMut`, which
+        // cannot coexist with this borrow
+        self.get_ptr::<R>().map(|resource| unsafe { &*resource })
+    }
+
+    pub(crate) fn get_mut<R: Resource>(&mut self) -> Option<&mut R> {
+        self.resources
+            .get_mut(&TypeId::of::<R>())
+            .and_then(|resource| resource.downcast_mut::<ResourceCell<R>>())
+            .map(|resource| resource.0.get_mut())
+    }
+
+    /// A pointer to the resource that can be written to while the manager is shared
+    pub(crate) fn get_ptr<R: Resource>(&self) -> Option<*mut R> {
+        self.resources
+            .get(&TypeId::of::<R>())
+            .and_then(|resource| resource.downcast_ref::<ResourceCell<R>>())
+            .map(|resource| resource.0.get())
+    }
+
+    pub(crate) fn remove<R: Resource>(&mut self) -> Option<R> {
+        self.resources.remove(&TypeId::of::<R>()).map(|resource| {
+            resource
+                .downcast::<ResourceCell<R>>()
+                .unwrap()
+                .0
+                .into_inner()
+        })
+    }
+}
+
+/// Shared access to the resource `R`.
+/// # Panics
+/// The system panics when run if the resource does not exist
+pub struct Res<'a, R: Resource>(&'a R);
+
+impl<R: Resource> Deref for Res<'_, R> {
+    type Target = R;
+
+    fn deref(&self) -> &Self::Target {
+        self.0
+    }
+}
+
+impl<'a, R: Resource> SystemParam for Res<'a, R> {
+    type State = ();
+
+    fn init_state(_: &mut SystemWorldArgs) -> Self::State {}
+
+    /// SAFETY: Cannot coexist with a `ResMut` of the same resource.
+    unsafe fn init(_: *mut Self::State, args: SystemRunArgs) -> Self {
+        let resources = unsafe { &*args.world.resources };
+        Self(
+            resources
+                .get::<R>()
+                .unwrap_or_else(|| missing_resource::<R>()),
+        )
+    }
+
+    fn safety_info(_: &mut SystemWorldArgs) -> Option<SafetyInfo> {
+        Some(SafetyInfo::Resource {
+            type_id: TypeId::of::<R>(),
+            type_name: std::any::type_name::<R>(),
+            mutable: false,
+        })
+    }
+}
+
+impl<R: Resource> ReadOnlySystemParam for Res<'_, R> {}
+
+/// Mutable access to the resource `R`.
+/// # Panics
+/// The system panics when run if the resource does not exist
+pub struct ResMut<'a, R: Resource>(&'a mut R);
+
+impl<R: Resource> Deref for ResMut<'_, R> {
+    type Target = R;
+
+    fn deref(&self) -> &Self::Target {
+        self.0
+    }
+}
+
+impl<R: Resource> DerefMut for ResMut<'_, R> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.0
+    }
+}
+
+impl<'a, R: Resource> SystemParam for ResMut<'a, R> {
+    type State = ();
+
+    fn init_state(_: &mut SystemWorldArgs) -> Self::State {}
+
+    /// SAFETY: Cannot coexist with another `Res` or `ResMut` of the same resource.
+    unsafe fn init(_: *mut Self::State, args: SystemRunArgs) -> Self {
+        let resources = unsafe { &*args.world.resources };
+        let resource = resources
+            .get_ptr::<R>()
+            .unwrap_or_else(|| missing_resource::<R>());
+        Self(unsafe { &mut *resource })
+    }
+
+    fn safety_info(_: &mut SystemWorldArgs) -> Option<SafetyInfo> {
+        Some(SafetyInfo::Resource {
+            type_id: TypeId::of::<R>(),
+            type_name: std::any::type_name::<R>(),
+            mutable: true,
+        })
+    }
+}
+
+fn missing_resource<R: Resource>() -> ! {
+    panic!(
+        "Resource {} does not exist in the world",
+        std::any::type_name::<R>()
+    )
+}
@@ -0,0 +1,278 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+    marker::PhantomData,
+};
+
+use crate::{
+    Commands, SystemWorldArgs,
+    system::{
+        Condition, IntoCondition, IntoSystem, SafetyCheck, System, SystemParamError, WorldPtrs,
+    },
+};
+
+/// Identifies a set of systems that run together, each with its own ordering
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ScheduleLabel {
+    /// Runs once, on the first `World::update`
+    Startup,
+    PreUpdate,
+    /// The schedule `World::add_system` adds systems to
+    Update,
+    PostUpdate,
+    /// Runs once for every fixed timestep that passed, independently of the frame rate
+    FixedUpdate,
+    /// Only run through `World::run_schedule`, unless it was placed among the schedules of
+    /// `World::update` with `World::insert_schedule_after`
+    Custom(&'static str),
+}
+
+/// A name systems can be given so other systems can be ordered relative to them
+pub type SystemLabel = &'static str;
+
+#[derive(Default, Debug, Clone)]
+pub(crate) struct SystemOrdering {
+    pub(crate) labels: Vec<SystemLabel>,
+    pub(crate) before: Vec<SystemLabel>,
+    pub(crate) after: Vec<SystemLabel>,
+}
+
+/// A system along with its labels, ordering constraints and run conditions
+pub struct SystemConfig<S: IntoSystem<T>, T> {
+    pub(crate) system: S,
+    pub(crate) ordering: SystemOrdering,
+    pub(crate) conditions: Vec<Box<dyn Condition>>,
+    _marker: PhantomData<fn() -> T>,
+}
+
+pub trait IntoSystemConfig<T, S: IntoSystem<T>>: Sized {
+    fn into_config(self) -> SystemConfig<S, T>;
+
+    fn label(self, label: SystemLabel) -> SystemConfig<S, T> {
+        let mut config = self.into_config();
+        config.ordering.labels.push(label);
+        config
+    }
+
+    /// Runs the system before every system with the label.
+    /// A label no system has is ignored.
+    fn before(self, label: SystemLabel) -> SystemConfig<S, T> {
+        let mut config = self.into_config();
+        config.ordering.before.push(label);
+        config
+    }
+
+    /// Runs the system after every system with the label.
+    /// A label no system has is ignored.
+    fn after(self, label: SystemLabel) -> SystemConfig<S, T> {
+        let mut config = self.into_config();
+        config.ordering.after.push(label);
+        config
+    }
+
+    /// Only runs the system when the condition returns `true`. The condition is checked before
+    /// the system's parameters are fetched. Every condition added must hold.
+    fn run_if<M>(self, condition: impl IntoCondition<M>) -> SystemConfig<S, T> {
+        let mut config = self.into_config();
+        config.conditions.push(condition.into_condition());
+        config
+    }
+}
+
+impl<T, S: IntoSystem<T>> IntoSystemConfig<T, S> for S {
+    fn into_config(self) -> SystemConfig<S, T> {
+        SystemConfig {
+            system: self,
+            ordering: SystemOrdering::default(),
+            conditions: Vec::new(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T, S: IntoSystem<T>> IntoSystemConfig<T, S> for SystemConfig<S, T> {
+    fn into_config(self) -> SystemConfig<S, T> {
+        self
+    }
+}
+
+/// A sync point: the commands queued by the systems that run before it are applied before the
+/// systems that run after it start. Added like a system and ordered with labels, for example
+/// `world.add_system(ApplyDeferred.after("spawn").before("count"))`.
+///
+/// Sync points are also inserted automatically before every system that must run after a system
+/// queuing commands.
+pub struct ApplyDeferred;
+
+impl IntoSystem<ApplyDeferred> for ApplyDeferred {
+    fn parse(self, _: &mut SystemWorldArgs) -> Result<Box<dyn System>, SystemParamError> {
+        Ok(Box::new(SyncPoint {
+            access: Some(SafetyCheck::new()),
+        }))
+    }
+
+    unsafe fn parse_unchecked(self) -> Box<dyn System> {
+        Box::new(SyncPoint { access: None })
+    }
+}
+
+/// The system added by `ApplyDeferred`. The executor splits the schedule at it instead of
+/// running it.
+struct SyncPoint {
+    access: Option<SafetyCheck>,
+}
+
+impl System for SyncPoint {
+    fn name(&self) -> &'static str {
+        "ApplyDeferred"
+    }
+
+    fn access(&self) -> Option<&SafetyCheck> {
+        self.access.as_ref()
+    }
+
+    fn set_access(&mut self, access: SafetyCheck) {
+        self.access = Some(access);
+    }
+
+    fn initialize(&mut self, _: &mut SystemWorldArgs) {}
+
+    fn is_initialized(&self) -> bool {
+        true
+    }
+
+    unsafe fn run(&mut self, _: WorldPtrs) {}
+
+    fn flush_commands(&mut self, _: &mut Commands) {}
+
+    fn is_sync_point(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScheduleBuildError {
+    /// The ordering constraints of these systems form a cycle. The first system is repeated at
+    /// the end.
+    Cycle(Vec<String>),
+}
+
+impl fmt::Display for ScheduleBuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Cycle(systems) => write!(
+                f,
+                "The ordering constraints of these systems form a cycle: {}",
+                systems.join(" -> ")
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ScheduleBuildError {}
+
+/// Chooses how the systems of a schedule are run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExecutorKind {
+    /// Runs systems that do not conflict with each other at the same time on multiple threads.
+    /// Systems that conflict still run in the order `Sequential` would run them in. Falls back
+    /// to running on the calling thread when only one thread is available, or for the parts of
+    /// the schedule between sync points that have a single system.
+    #[default]
+    Parallel,
+    /// Runs every system one after the other on the calling thread
+    Sequential,
+}
+
+/// The systems sorted by their ordering constraints
+pub(crate) struct SortedSystems {
+    pub(crate) order: Box<[usize]>,
+    /// successors[a] contains b if a must run before b
+    pub(crate) successors: Box<[BTreeSet<usize>]>,
+}
+
+/// Sorts the systems so every system runs after the ones it must come after.
+/// Systems without constraints between them keep the order they were added in.
+pub(crate) fn topological_order(
+    names: &[&str],
+    orderings: &[SystemOrdering],
+) -> Result<SortedSystems, ScheduleBuildError> {
+    let mut labeled: HashMap<SystemLabel, Vec<usize>> = HashMap::new();
+    for (system, ordering) in orderings.iter().enumerate() {
+        for &label in &ordering.labels {
+            labeled.entry(label).or_default().push(system);
+        }
+    }
+
+    let mut successors: Vec<BTreeSet<usize>> = vec![BTreeSet::new(); orderings.len()];
+    for (system, ordering) in orderings.iter().enumerate() {
+        for label in &ordering.before {
+            for &other in labeled.get(label).into_iter().flatten() {
+                successors[system].insert(other);
+            }
+        }
+        for label in &ordering.after {
+            for &other in labeled.get(label).into_iter().flatten() {
+                successors[other].insert(system);
+            }
+        }
+    }
+
+    let mut predecessors_left = vec![0usize; orderings.len()];
+    for &successor in successors.iter().flatten() {
+        predecessors_left[successor] += 1;
+    }
+
+    // Always picking the earliest added ready system keeps the insertion order stable
+    let mut ready: BTreeSet<usize> = (0..orderings.len())
+        .filter(|&system| predecessors_left[system] == 0)
+        .collect();
+    let mut order = Vec::with_capacity(orderings.len());
+    while let Some(system) = ready.pop_first() {
+        order.push(system);
+        for &successor in &successors[system] {
+            predecessors_left[successor] -= 1;
+            if predecessors_left[successor] == 0 {
+                ready.insert(successor);
+            }
+        }
+    }
+
+    if order.len() == orderings.len() {
+        return Ok(SortedSystems {
+            order: order.into_boxed_slice(),
+            successors: successors.into_boxed_slice(),
+        });
+    }
+
+    let cycle = find_cycle(&successors, &predecessors_left);
+    Err(ScheduleBuildError::Cycle(
+        cycle
+            .into_iter()
+            .map(|system| names[system].to_owned())
+            .collect(),
+    ))
+}
+
+/// Walks the systems that could not be sorted until one repeats. Every such system has an
+/// unsorted predecessor, so walking backwards always ends in a cycle.
+fn find_cycle(successors: &[BTreeSet<usize>], predecessors_left: &[usize]) -> Vec<usize> {
+    let unsorted = |system: usize| predecessors_left[system] > 0;
+    let predecessor = |system: usize| {
+        (0..successors.len())
+            .find(|&other| unsorted(other) && successors[other].contains(&system))
+            .unwrap()
+    };
+
+    let mut path = vec![(0..successors.len()).find(|&s| unsorted(s)).unwrap()];
+    loop {
+        let next = predecessor(*path.last().unwrap());
+        if let Some(start) = path.iter().position(|&system| system == next) {
+            let mut cycle = path.split_off(start);
+            cycle.reverse();
+            cycle.push(cycle[0]);
+            return cycle;
+        }
+        path.push(next);
+    }
+}
@@ -1,24 +1,49 @@
 use std::{
-    collections::HashMap,
+    any::TypeId,
+    collections::{BTreeSet, HashMap, VecDeque},
     fmt::{self, Debug},
+    marker::PhantomData,
+    num::NonZero,
+    panic::{self, AssertUnwindSafe},
+    sync::{Mutex, OnceLock, mpsc},
+    thread,
 };
 
-use crate::{SystemWorldArgs, component::ComponentId, entity::EntityBitmask, query::QueryInfo};
+use crate::{
+    Commands, SystemWorldArgs, World,
+    change_detection::{SystemTicks, Tick},
+    component::{ComponentId, ComponentManager},
+    entity::EntityManager,
+    query::QueryInfo,
+    resource::ResourceManager,
+    schedule::{
+        ExecutorKind, IntoSystemConfig, ScheduleBuildError, SortedSystems, SystemOrdering,
+        topological_order,
+    },
+};
 
 pub(crate) enum SafetyInfo {
     Commands,
     Query(QueryInfo),
+    Resource {
+        type_id: TypeId,
+        type_name: &'static str,
+        mutable: bool,
+    },
 }
 
-#[derive(Default)]
-pub(crate) struct SafetyCheck {
-    /// This is a hashmap with a queried component as key and restrictions to its query as value
-    /// The usage is pretty simple:
-    /// If there's a component being queried two times, then it must have one of its components in
-    /// the restrictions
-    consumed_bitmasks: HashMap<ComponentId, EntityBitmask>,
-    /// There can be only one commands in each query
+/// The components, resources and commands a system accesses
+#[derive(Default, Clone)]
+pub struct SafetyCheck {
+    /// Every query checked so far.
+    /// Two queries conflict if one of them writes a component the other accesses, unless the
+    /// restrictions of one of them exclude a component the other requires
+    queries: Vec<QueryInfo>,
+    /// There can be only one commands in each system. Every system queues into its own buffer,
+    /// so the commands of different systems never conflict.
     has_commands: bool,
+    /// Every resource accessed so far and whether it was accessed mutably
+    resources: HashMap<TypeId, bool>,
 }
 
 impl SafetyCheck {
@@ -28,24 +53,15 @@ impl SafetyCheck {
 
     pub(crate) fn check_query<P: SystemParam>(
         &mut self,
-        info: &QueryInfo,
+        info: QueryInfo,
     ) -> Result<(), SystemParamError> {
-        for component in info.query_bitmask.iter() {
-            if let Some(restriction) = self.consumed_bitmasks.get_mut(&component) {
-                if info
-                    .query_bitmask
-                    .intersection(restriction)
-                    .next()
-                    .is_none()
-                {
-                    return Err(SystemParamError::new_query_error::<P>(component));
-                }
-                restriction.difference_with(&info.query_bitmask);
-            } else {
-                self.consumed_bitmasks
-                    .insert(component, info.restrictions_bitmask.clone());
+        for other in &self.queries {
+            if queries_conflict(&info, other) {
+                let component = info.conflicting_component(other).unwrap();
+                return Err(SystemParamError::new_query_error::<P>(component));
             }
         }
+        self.queries.push(info);
         Ok(())
     }
 
@@ -57,23 +73,86 @@ impl SafetyCheck {
         Ok(())
     }
 
+    pub(crate) fn check_resource(
+        &mut self,
+        type_id: TypeId,
+        type_name: &'static str,
+        mutable: bool,
+    ) -> Result<(), SystemParamError> {
+        if let Some(previous_mutable) = self.resources.insert(type_id, mutable)
+            && (previous_mutable || mutable)
+        {
+            return Err(SystemParamError::ConflictingResourceAccess {
+                resource_name: type_name.into(),
+            });
+        }
+        Ok(())
+    }
+
     pub(crate) fn check<P: SystemParam>(
         &mut self,
         info: SafetyInfo,
     ) -> Result<(), SystemParamError> {
         match info {
             SafetyInfo::Commands => self.check_commands(),
-            SafetyInfo::Query(query_info) => self.check_query::<P>(&query_info),
+            SafetyInfo::Query(query_info) => self.check_query::<P>(query_info),
+            SafetyInfo::Resource {
+                type_id,
+                type_name,
+                mutable,
+            } => self.check_resource(type_id, type_name, mutable),
         }
     }
+
+    /// Whether the system queues commands, which are only applied at the next sync point
+    pub(crate) fn has_commands(&self) -> bool {
+        self.has_commands
+    }
+
+    /// Adds the accesses of `other` without checking them against each other
+    pub(crate) fn extend(&mut self, other: SafetyCheck) {
+        self.queries.extend(other.queries);
+        self.has_commands |= other.has_commands;
+        for (type_id, mutable) in other.resources {
+            *self.resources.entry(type_id).or_default() |= mutable;
+        }
+    }
+
+    /// Whether running two systems with these accesses at the same time could alias a mutable
+    /// reference
+    pub(crate) fn conflicts_with(&self, other: &SafetyCheck) -> bool {
+        self.resources.iter().any(|(type_id, &mutable)| {
+            other
+                .resources
+                .get(type_id)
+                .is_some_and(|&other_mutable| mutable || other_mutable)
+        }) || self.queries.iter().any(|query| {
+            other
+                .queries
+                .iter()
+                .any(|other| queries_conflict(query, other))
+        })
+    }
+}
+
+fn queries_conflict(query: &QueryInfo, other: &QueryInfo) -> bool {
+    query.conflicting_component(other).is_some() && !query.is_disjoint(other)
 }
 
 pub(crate) trait SystemParam {
-    unsafe fn init(args: *mut SystemWorldArgs) -> Self;
+    /// Data kept by the system between runs, created on its first run
+    type State: Send + 'static;
+    fn init_state(args: &mut SystemWorldArgs) -> Self::State;
+    /// SAFETY: The parameter may only access what `safety_info` declares, and the executor must
+    /// not run it alongside a parameter with a conflicting access
+    unsafe fn init(state: *mut Self::State, args: SystemRunArgs) -> Self;
     fn safety_info(args: &mut SystemWorldArgs) -> Option<SafetyInfo>;
 }
 
-pub trait IntoSystem<T> {
+/// A parameter that never mutates the world, so it can be used by run conditions
+pub(crate) trait ReadOnlySystemParam: SystemParam {}
+
+pub trait IntoSystem<T>: Sized {
     fn parse(self, args: &mut SystemWorldArgs) -> Result<Box<dyn System>, SystemParamError>;
     /// SAFETY: Calling this function from outside `IntoSystem::parse` might lead to multiple
     /// mutable references to the same value.
@@ -81,10 +160,10 @@ pub trait IntoSystem<T> {
 }
 
 macro_rules! impl_into_system {
-    ($($A:ident),*) => {
+    ($(($n:tt, $A:ident)),*) => {
         impl<F, $($A: SystemParam,)*> IntoSystem<($($A,)*)> for F
         where
-            F: Fn($($A,)*) + 'static
+            F: Fn($($A,)*) + Send + 'static
         {
             #[allow(unused_variables, unused_mut)]
             fn parse(self, args: &mut SystemWorldArgs) -> Result<Box<dyn System>, SystemParamError> {
@@ -95,78 +174,607 @@ macro_rules! impl_into_system {
                     }
                 )*
                 // SAFETY:
-                //     - No two queries may query the same component
-                //     - A component queried by a certain query must be
-                //         in the restrictions of the others
+                //     - No two queries may access the same component if one of them
+                //         mutates it, unless their restrictions keep them disjoint
                 //     - No two mutable references to Commands may coexist
-                Ok(unsafe {self.parse_unchecked()})
+                //     - A mutably borrowed resource may not be borrowed again
+                let mut system = unsafe { self.parse_unchecked() };
+                system.set_access(safety_check);
+                Ok(system)
             }
 
-            #[allow(unused_variables)]
+            #[allow(unused_variables, clippy::unused_unit)]
             unsafe fn parse_unchecked(self) -> Box<dyn System> {
-                Box::new(SystemWrapper::new(move |args: &mut SystemWorldArgs| self($(unsafe {$A::init(args)},)*)))
+                Box::new(SystemWrapper::new(
+                    std::any::type_name::<F>(),
+                    |args: &mut SystemWorldArgs| ($($A::init_state(args),)*),
+                    move |state: &mut ($($A::State,)*), args: SystemRunArgs| {
+                        self($(unsafe {$A::init(&mut state.$n, args)},)*)
+                    },
+                ))
             }
         }
     };
 }
 
-variadics_please::all_tuples!(impl_into_system, 0, 15, A);
+variadics_please::all_tuples_enumerated!(impl_into_system, 0, 15, A);
 
-pub trait System: 'static {
-    fn run(&self, args: &mut SystemWorldArgs);
+/// A read-only system returning whether the system it is attached to should run
+pub trait IntoCondition<T> {
+    fn into_condition(self) -> Box<dyn Condition>;
 }
 
-pub(crate) struct SystemWrapper<F: Fn(&mut SystemWorldArgs)> {
+macro_rules! impl_into_condition {
+    ($(($n:tt, $A:ident)),*) => {
+        impl<F, $($A: ReadOnlySystemParam,)*> IntoCondition<($($A,)*)> for F
+        where
+            F: Fn($($A,)*) -> bool + Send + 'static
+        {
+            #[allow(unused_variables, unused_mut, clippy::unused_unit)]
+            fn into_condition(self) -> Box<dyn Condition> {
+                Box::new(ConditionWrapper {
+                    access: |args: &mut SystemWorldArgs| {
+                        let mut access = SafetyCheck::new();
+                        $(
+                            if let Some(info) = $A::safety_info(args) {
+                                access
+                                    .check::<$A>(info)
+                                    .expect("read-only parameters never conflict");
+                            }
+                        )*
+                        access
+                    },
+                    system: SystemWrapper::new(
+                        std::any::type_name::<F>(),
+                        |args: &mut SystemWorldArgs| ($($A::init_state(args),)*),
+                        // SAFETY: Read-only parameters never alias a mutable reference
+                        move |state: &mut ($($A::State,)*), args: SystemRunArgs| {
+                            self($(unsafe {$A::init(&mut state.$n, args)},)*)
+                        },
+                    ),
+                })
+            }
+        }
+    };
+}
+
+variadics_please::all_tuples_enumerated!(impl_into_condition, 0, 15, A);
+
+pub trait Condition: Send + 'static {
+    /// The accesses of the condition's parameters, registering their components if needed
+    fn access(&self, args: &mut SystemWorldArgs) -> SafetyCheck;
+    /// Creates the condition's state if it has not been created yet
+    fn initialize(&mut self, args: &mut SystemWorldArgs);
+    /// SAFETY: The condition must have been initialized, and nothing may access the world
+    /// mutably in a way that conflicts with it while it runs
+    unsafe fn evaluate(&mut self, world: WorldPtrs) -> bool;
+}
+
+struct ConditionWrapper<S, F> {
+    access: fn(&mut SystemWorldArgs) -> SafetyCheck,
+    system: SystemWrapper<S, F, bool>,
+}
+
+impl<S, F> Condition for ConditionWrapper<S, F>
+where
+    S: Send + 'static,
+    F: FnMut(&mut S, SystemRunArgs) -> bool + Send + 'static,
+{
+    fn access(&self, args: &mut SystemWorldArgs) -> SafetyCheck {
+        (self.access)(args)
+    }
+
+    fn initialize(&mut self, args: &mut SystemWorldArgs) {
+        self.system.initialize(args);
+    }
+
+    unsafe fn evaluate(&mut self, world: WorldPtrs) -> bool {
+        unsafe { self.system.call(world) }
+    }
+}
+
+pub trait System: Send + 'static {
+    fn name(&self) -> &'static str;
+    /// What the system accesses, or `None` if it was added unchecked, in which case it has to
+    /// be assumed to conflict with every other system
+    fn access(&self) -> Option<&SafetyCheck>;
+    fn set_access(&mut self, access: SafetyCheck);
+    /// Creates the system's state if it has not been created yet. Running the system does this
+    /// too.
+    fn initialize(&mut self, args: &mut SystemWorldArgs);
+    /// Whether the system's state was created
+    fn is_initialized(&self) -> bool;
+    /// SAFETY: The system must have been initialized, and nothing may access the world mutably
+    /// in a way that conflicts with it while it runs
+    unsafe fn run(&mut self, world: WorldPtrs);
+    /// Moves the commands queued by the system since its last flush to the end of `commands`
+    fn flush_commands(&mut self, commands: &mut Commands);
+    /// Whether this is a sync point added with `ApplyDeferred` rather than a system to run
+    fn is_sync_point(&self) -> bool {
+        false
+    }
+}
+
+pub(crate) struct SystemWrapper<S, F, Out = ()> {
+    name: &'static str,
+    access: Option<SafetyCheck>,
+    state: Option<S>,
+    /// The buffer the system's `Commands` parameter queues into, created with its state
+    commands: Option<Commands>,
+    /// The change tick of the last run, so changes made since then can be detected
+    last_run: Tick,
+    init_state: fn(&mut SystemWorldArgs) -> S,
     fptr: F,
+    _marker: PhantomData<fn() -> Out>,
 }
 
-impl<F: Fn(&mut SystemWorldArgs)> SystemWrapper<F> {
-    pub(crate) fn new(fptr: F) -> Self {
-        Self { fptr }
+impl<S, F, Out> SystemWrapper<S, F, Out> {
+    pub(crate) fn new(
+        name: &'static str,
+        init_state: fn(&mut SystemWorldArgs) -> S,
+        fptr: F,
+    ) -> Self {
+        Self {
+            name,
+            access: None,
+            state: None,
+            commands: None,
+            last_run: 0,
+            init_state,
+            fptr,
+            _marker: PhantomData,
+        }
+    }
+
+    pub(crate) fn initialize(&mut self, args: &mut SystemWorldArgs) {
+        if self.state.is_none() {
+            self.state = Some((self.init_state)(args));
+            self.commands = Some(Commands::new(args.entity_manager.allocator()));
+        }
+    }
+
+    pub(crate) fn flush_commands(&mut self, commands: &mut Commands) {
+        if let Some(queued) = &mut self.commands {
+            commands.append(queued);
+        }
+    }
+
+    /// SAFETY: See `System::run`
+    unsafe fn call(&mut self, world: WorldPtrs) -> Out
+    where
+        F: FnMut(&mut S, SystemRunArgs) -> Out,
+    {
+        unsafe { self.call_with(world, |fptr, state, args| fptr(state, args)) }
+    }
+
+    /// Runs the system through `call`, which gets its function, state and arguments, so the
+    /// function can take more than them.
+    /// SAFETY: See `System::run`
+    pub(crate) unsafe fn call_with(
+        &mut self,
+        world: WorldPtrs,
+        call: impl FnOnce(&mut F, &mut S, SystemRunArgs) -> Out,
+    ) -> Out {
+        let (Some(state), Some(commands)) = (&mut self.state, &mut self.commands) else {
+            panic!("The system {} was run before being initialized", self.name);
+        };
+        let this_run = unsafe { (*world.entity_manager).increment_change_tick() };
+        // The system queues its commands into its own buffer
+        let args = SystemRunArgs {
+            world,
+            commands,
+            ticks: SystemTicks {
+                last_run: self.last_run,
+                this_run,
+            },
+        };
+        let out = call(&mut self.fptr, state, args);
+        self.last_run = this_run;
+        out
     }
 }
 
-impl<F: Fn(&mut SystemWorldArgs) + 'static> System for SystemWrapper<F> {
-    fn run(&self, args: &mut SystemWorldArgs) {
-        (self.fptr)(args)
+impl<S, F> System for SystemWrapper<S, F>
+where
+    S: Send + 'static,
+    F: FnMut(&mut S, SystemRunArgs) + Send + 'static,
+{
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn access(&self) -> Option<&SafetyCheck> {
+        self.access.as_ref()
+    }
+
+    fn set_access(&mut self, access: SafetyCheck) {
+        self.access = Some(access);
+    }
+
+    fn initialize(&mut self, args: &mut SystemWorldArgs) {
+        SystemWrapper::initialize(self, args);
+    }
+
+    fn is_initialized(&self) -> bool {
+        self.state.is_some()
+    }
+
+    unsafe fn run(&mut self, world: WorldPtrs) {
+        unsafe { self.call(world) }
+    }
+
+    fn flush_commands(&mut self, commands: &mut Commands) {
+        SystemWrapper::flush_commands(self, commands);
     }
 }
 
+/// The order the systems of a schedule run in and what each of them has to wait for
+struct BuiltSchedule {
+    /// The systems in the order they run in, split at sync points. The commands queued by the
+    /// systems of a segment are applied before the next segment starts.
+    segments: Box<[Box<[usize]>]>,
+    /// For every system, the systems of its segment that can only start after it finished
+    dependents: Box<[Vec<usize>]>,
+    /// For every system, how many systems of its segment it has to wait for
+    dependency_counts: Box<[usize]>,
+}
+
 #[derive(Default)]
 pub(crate) struct SystemsManager {
     systems: Vec<Box<dyn System>>,
+    orderings: Vec<SystemOrdering>,
+    /// Every condition must hold for the system at the same index to run
+    conditions: Vec<Vec<Box<dyn Condition>>>,
+    /// What every system and its conditions access, or `None` for unchecked systems
+    accesses: Vec<Option<SafetyCheck>>,
+    /// `None` if systems were added since the schedule was built
+    built: Option<BuiltSchedule>,
+    /// `None` until an executor is chosen, so appending a schedule knows whether it chose one
+    pub(crate) executor: Option<ExecutorKind>,
 }
 
 impl SystemsManager {
-    pub(crate) fn add_system<T>(
+    pub(crate) fn add_system<T, S: IntoSystem<T>>(
         &mut self,
         mut args: SystemWorldArgs,
-        system: impl IntoSystem<T>,
+        system: impl IntoSystemConfig<T, S>,
     ) -> Result<(), SystemParamError> {
-        self.systems.push(system.parse(&mut args)?);
+        let config = system.into_config();
+        let system = config.system.parse(&mut args)?;
+        let mut access = system.access().cloned().unwrap_or_default();
+        for condition in &config.conditions {
+            access.extend(condition.access(&mut args));
+        }
+        self.push(system, config.ordering, config.conditions, Some(access));
+        Ok(())
+    }
+
+    pub(crate) unsafe fn add_system_unchecked<T, S: IntoSystem<T>>(
+        &mut self,
+        system: impl IntoSystemConfig<T, S>,
+    ) {
+        let config = system.into_config();
+        self.push(
+            unsafe { config.system.parse_unchecked() },
+            config.ordering,
+            config.conditions,
+            None,
+        );
+    }
+
+    fn push(
+        &mut self,
+        system: Box<dyn System>,
+        ordering: SystemOrdering,
+        conditions: Vec<Box<dyn Condition>>,
+        access: Option<SafetyCheck>,
+    ) {
+        self.systems.push(system);
+        self.orderings.push(ordering);
+        self.conditions.push(conditions);
+        self.accesses.push(access);
+        self.built = None;
+    }
+
+    /// Moves the systems of `other` after the ones of this schedule, along with its executor if
+    /// one was chosen
+    pub(crate) fn append(&mut self, other: SystemsManager) {
+        if other.executor.is_some() {
+            self.executor = other.executor;
+        }
+        for (((system, ordering), conditions), access) in other
+            .systems
+            .into_iter()
+            .zip(other.orderings)
+            .zip(other.conditions)
+            .zip(other.accesses)
+        {
+            self.push(system, ordering, conditions, access);
+        }
+    }
+
+    /// Sorts the systems according to their ordering constraints
+    pub(crate) fn build(&mut self) -> Result<(), ScheduleBuildError> {
+        if self.built.is_some() {
+            return Ok(());
+        }
+        let names: Vec<_> = self.systems.iter().map(|system| system.name()).collect();
+        let SortedSystems { order, successors } = topological_order(&names, &self.orderings)?;
+        let segments = self.split_at_sync_points(&order, &successors);
+
+        // A system waits for the earlier systems of its segment it must run after or that it
+        // conflicts with, so conflicting systems run in the same order as they would sequentially
+        let mut dependents = vec![Vec::new(); order.len()].into_boxed_slice();
+        let mut dependency_counts = vec![0; order.len()].into_boxed_slice();
+        for segment in &segments {
+            for (position, &system) in segment.iter().enumerate() {
+                for &earlier in &segment[..position] {
+                    let conflicts = match (&self.accesses[earlier], &self.accesses[system]) {
+                        (Some(earlier_access), Some(access)) => {
+                            earlier_access.conflicts_with(access)
+                        }
+                        _ => true,
+                    };
+                    if conflicts || successors[earlier].contains(&system) {
+                        dependents[earlier].push(system);
+                        dependency_counts[system] += 1;
+                    }
+                }
+            }
+        }
+
+        self.built = Some(BuiltSchedule {
+            segments,
+            dependents,
+            dependency_counts,
+        });
         Ok(())
     }
 
-    pub(crate) unsafe fn add_system_unchecked<T>(&mut self, system: impl IntoSystem<T>) {
-        unsafe { self.systems.push(system.parse_unchecked()) };
+    /// Splits the sorted systems at every `ApplyDeferred`, and before every system that must
+    /// run after a system of the same segment that queues commands, so it sees their effects.
+    /// Unchecked systems are assumed to queue commands.
+    fn split_at_sync_points(
+        &self,
+        order: &[usize],
+        successors: &[BTreeSet<usize>],
+    ) -> Box<[Box<[usize]>]> {
+        let mut segments = Vec::new();
+        let mut segment = Vec::new();
+        for &system in order {
+            let after_commands = || {
+                segment.iter().any(|&earlier: &usize| {
+                    successors[earlier].contains(&system)
+                        && self.accesses[earlier]
+                            .as_ref()
+                            .is_none_or(SafetyCheck::has_commands)
+                })
+            };
+            if (self.systems[system].is_sync_point() || after_commands()) && !segment.is_empty() {
+                segments.push(std::mem::take(&mut segment).into_boxed_slice());
+            }
+            if !self.systems[system].is_sync_point() {
+                segment.push(system);
+            }
+        }
+        if !segment.is_empty() {
+            segments.push(segment.into_boxed_slice());
+        }
+        segments.into_boxed_slice()
+    }
+
+    /// Runs the systems segment by segment, applying the commands queued by the systems of a
+    /// segment before the next one starts
+    pub(crate) fn run_all(&mut self, world: &mut World) {
+        if let Err(error) = self.build() {
+            panic!("{error}");
+        }
+        let threads = available_threads();
+        for segment in 0..self.built.as_ref().unwrap().segments.len() {
+            let mut args = SystemWorldArgs::new(
+                &mut world.components_manager,
+                &mut world.entity_manager,
+                &mut world.resources,
+            );
+            // Spawning threads is not worth it when there is nothing to run alongside
+            let segment_len = self.built.as_ref().unwrap().segments[segment].len();
+            match self.executor.unwrap_or_default() {
+                ExecutorKind::Parallel if threads > 1 && segment_len > 1 => {
+                    self.run_parallel(&mut args, segment, threads)
+                }
+                ExecutorKind::Parallel | ExecutorKind::Sequential => {
+                    self.run_sequential(&mut args, segment)
+                }
+            }
+
+            // The commands are queued in schedule order, whichever order the systems finished in
+            for &system in &self.built.as_ref().unwrap().segments[segment] {
+                self.systems[system].flush_commands(&mut world.commands);
+            }
+            world.apply_commands();
+        }
+    }
+
+    fn run_sequential(&mut self, args: &mut SystemWorldArgs, segment: usize) {
+        for &system in &self.built.as_ref().unwrap().segments[segment] {
+            // Conditions run first so a skipped system never initializes its parameters.
+            // SAFETY: Only one system runs at a time, and `args` is not used while it runs.
+            if self.conditions[system].iter_mut().all(|condition| {
+                condition.initialize(args);
+                unsafe { condition.evaluate(args.ptrs()) }
+            }) {
+                self.systems[system].initialize(args);
+                unsafe { self.systems[system].run(args.ptrs()) };
+            }
+        }
     }
 
-    pub(crate) fn run_all(&self, mut args: SystemWorldArgs) {
-        for system in &self.systems {
-            system.run(&mut args);
+    fn run_parallel(&mut self, args: &mut SystemWorldArgs, segment: usize, threads: usize) {
+        let built = self.built.as_ref().unwrap();
+        let segment = &built.segments[segment];
+        // States may register components, so they are created before anything runs in parallel.
+        // Conditions are evaluated on this thread once their system is ready, and a system with
+        // conditions is only initialized after they first pass, like with the sequential executor.
+        for &system in segment {
+            if self.conditions[system].is_empty() {
+                self.systems[system].initialize(args);
+            }
+            for condition in &mut self.conditions[system] {
+                condition.initialize(args);
+            }
+        }
+
+        let mut dependencies_left = built.dependency_counts.to_vec();
+        let mut ready: VecDeque<usize> = segment
+            .iter()
+            .copied()
+            .filter(|&system| dependencies_left[system] == 0)
+            .collect();
+        let mut tasks: Vec<_> = self.systems.iter_mut().map(Some).collect();
+        let conditions = &mut self.conditions;
+        let world = args.ptrs();
+        let (job_sender, job_receiver) = mpsc::channel::<(usize, &mut Box<dyn System>)>();
+        let job_receiver = Mutex::new(job_receiver);
+        let (done_sender, done_receiver) = mpsc::channel();
+
+        let mut panic_payload = None;
+        thread::scope(|scope| {
+            // A fixed set of workers takes the ready systems, so no thread is spawned per system.
+            // They stop once the job sender is dropped at the end of the scope.
+            for _ in 0..threads.min(segment.len()) {
+                let (job_receiver, done_sender) = (&job_receiver, done_sender.clone());
+                scope.spawn(move || {
+                    loop {
+                        // The lock is released as soon as a job is received
+                        let job = job_receiver.lock().unwrap().recv();
+                        let Ok((system, task)) = job else {
+                            break;
+                        };
+                        // SAFETY: The system was initialized, and systems that conflict never
+                        // run at the same time
+                        let result =
+                            panic::catch_unwind(AssertUnwindSafe(|| unsafe { task.run(world) }));
+                        let _ = done_sender.send((system, result.err()));
+                    }
+                });
+            }
+
+            let mut running = 0;
+            // A system whose conditions passed but which has to wait for every running system to
+            // finish, since initializing it needs exclusive access to the world
+            let mut uninitialized: Option<(usize, &mut Box<dyn System>)> = None;
+            loop {
+                if panic_payload.is_none() {
+                    if running == 0
+                        && let Some((system, task)) = uninitialized.take()
+                    {
+                        task.initialize(args);
+                        job_sender.send((system, task)).unwrap();
+                        running += 1;
+                    }
+                    while uninitialized.is_none()
+                        && let Some(system) = ready.pop_front()
+                    {
+                        // SAFETY: Conditions only read the world, and the systems they conflict
+                        // with are not running since their system is ready
+                        if !conditions[system]
+                            .iter_mut()
+                            .all(|condition| unsafe { condition.evaluate(world) })
+                        {
+                            release_dependents(built, &mut dependencies_left, &mut ready, system);
+                            continue;
+                        }
+                        let task = tasks[system].take().unwrap();
+                        if !task.is_initialized() {
+                            if running > 0 {
+                                uninitialized = Some((system, task));
+                                break;
+                            }
+                            task.initialize(args);
+                        }
+                        job_sender.send((system, task)).unwrap();
+                        running += 1;
+                    }
+                }
+                if running == 0 {
+                    break;
+                }
+
+                let (system, payload) = done_receiver.recv().unwrap();
+                running -= 1;
+                if payload.is_some() {
+                    // Let the running systems finish, but do not start new ones
+                    if panic_payload.is_none() {
+                        panic_payload = payload;
+                    }
+                    continue;
+                }
+                release_dependents(built, &mut dependencies_left, &mut ready, system);
+            }
+            drop(job_sender);
+        });
+        if let Some(payload) = panic_payload {
+            panic::resume_unwind(payload);
         }
+    }
+}
 
-        args.commands
-            .apply(args.entity_manager, args.components_manager);
+/// Marks `system` as finished, readying the systems that only waited for it
+fn release_dependents(
+    built: &BuiltSchedule,
+    dependencies_left: &mut [usize],
+    ready: &mut VecDeque<usize>,
+    system: usize,
+) {
+    for &dependent in &built.dependents[system] {
+        dependencies_left[dependent] -= 1;
+        if dependencies_left[dependent] == 0 {
+            ready.push_back(dependent);
+        }
     }
 }
 
+/// The number of threads systems and parallel queries can use, looked up once
+pub(crate) fn available_threads() -> usize {
+    static THREADS: OnceLock<usize> = OnceLock::new();
+    *THREADS.get_or_init(|| thread::available_parallelism().map_or(1, NonZero::get))
+}
+
+/// The parts of the world a running system can reach, shared by the systems running on other
+/// threads. Only shared references to the managers are ever created from them: parameters
+/// derive mutable access to the single column or resource they declared in their
+/// `SafetyInfo`, so systems that do not conflict never alias a mutable reference.
+#[derive(Clone, Copy)]
+pub struct WorldPtrs {
+    pub(crate) components_manager: *const ComponentManager,
+    pub(crate) entity_manager: *const EntityManager,
+    pub(crate) resources: *const ResourceManager,
+}
+
+// SAFETY: Components and resources are `Send + Sync`, and the executor never runs two systems
+// whose accesses conflict at the same time
+unsafe impl Send for WorldPtrs {}
+unsafe impl Sync for WorldPtrs {}
+
+/// What the parameters of a running system are created from
+#[derive(Clone, Copy)]
+pub struct SystemRunArgs {
+    pub(crate) world: WorldPtrs,
+    /// The buffer of the system being run, which no other system queues into
+    pub(crate) commands: *mut Commands,
+    /// The ticks of the system being run
+    pub(crate) ticks: SystemTicks,
+}
+
 pub enum SystemParamError {
     DuplicateCommands,
     MustRestrictQuery {
         query_string: String,
         component_id: ComponentId,
     },
+    ConflictingResourceAccess {
+        resource_name: String,
+    },
 }
 
 impl Debug for SystemParamError {
@@ -179,6 +787,9 @@ impl Debug for SystemParamError {
             } => f.write_fmt(format_args!(
                 "MustRestrict Error for query {query_string} in component with ID {component_id}",
             )),
+            Self::ConflictingResourceAccess { resource_name } => f.write_fmt(format_args!(
+                "ConflictingResourceAccess Error for resource {resource_name}, which is borrowed mutably alongside another borrow",
+            )),
         }
     }
 }
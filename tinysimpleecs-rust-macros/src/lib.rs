@@ -1,19 +1,73 @@
 extern crate proc_macro;
 
+use darling::FromDeriveInput;
 use proc_macro::TokenStream;
 use quote::quote;
 
-#[proc_macro_derive(Component)]
+#[derive(FromDeriveInput)]
+#[darling(attributes(component))]
+struct ComponentOpts {
+    ident: syn::Ident,
+    on_add: Option<syn::Path>,
+    on_insert: Option<syn::Path>,
+    on_remove: Option<syn::Path>,
+}
+
+#[proc_macro_derive(Component, attributes(component))]
 pub fn derive_component(item: TokenStream) -> TokenStream {
     let input = syn::parse_macro_input!(item as syn::DeriveInput);
-    let ident = &input.ident;
+    let opts = match ComponentOpts::from_derive_input(&input) {
+        Ok(opts) => opts,
+        Err(error) => return error.write_errors().into(),
+    };
+    let ident = &opts.ident;
+    if opts.on_add.is_none() && opts.on_insert.is_none() && opts.on_remove.is_none() {
+        return quote! {
+            impl Component for #ident {}
+        }
+        .into();
+    }
+
+    // The hooks are named by their full path so they do not have to be imported. The crate
+    // names itself the same way for its own components.
+    let on_add = opts.on_add.iter();
+    let on_insert = opts.on_insert.iter();
+    let on_remove = opts.on_remove.iter();
     let component_impl = quote! {
-        impl Component for #ident {}
+        impl Component for #ident {
+            fn register_hooks(hooks: &mut ::tinysimpleecs_rust::ComponentHooks) {
+                #(hooks.on_add(#on_add);)*
+                #(hooks.on_insert(#on_insert);)*
+                #(hooks.on_remove(#on_remove);)*
+            }
+        }
     };
 
     component_impl.into()
 }
 
+#[proc_macro_derive(Resource)]
+pub fn derive_resource(item: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(item as syn::DeriveInput);
+    let ident = &input.ident;
+    let resource_impl = quote! {
+        impl Resource for #ident {}
+    };
+
+    resource_impl.into()
+}
+
+#[proc_macro_derive(Event)]
+pub fn derive_event(item: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(item as syn::DeriveInput);
+    let ident = &input.ident;
+    let event_impl = quote! {
+        impl Event for #ident {}
+    };
+
+    event_impl.into()
+}
+
 // #[proc_macro]
 // pub fn implement_component_bundle(item: TokenStream) -> TokenStream {
 //     let input = syn::parse_macro_input!(item with syn::punctuated::Punctuated::<syn::Expr, syn::Token![,]>::parse_terminated);